@@ -0,0 +1,117 @@
+//! Compares copying a song's segments in with the binary format used by
+//! [`database::DatabaseTx::insert_new_song`] against the csv format that was used before it
+//!
+//! This needs a database that `migrate` has been run on, given by `PLINK_BENCH_DATABASE_URL`,
+//! and does nothing without one. Every insert is rolled back, so nothing is left behind.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use database::{models, Database};
+use sqlx::{Connection, PgConnection};
+
+/// About 20 seconds of audio with the cli's default spectrogram config
+const N_FRAMES: usize = 600;
+const N_BINS: usize = 640;
+const FFT_SIZE: usize = 1280;
+const FFT_OVERLAP: usize = 320;
+const SAMPLERATE: usize = 30_000;
+
+fn frames() -> Vec<Vec<f32>> {
+    (0..N_FRAMES)
+        .map(|frame| {
+            (0..N_BINS)
+                .map(|bin| ((frame * N_BINS + bin) as f32 * 0.618).fract())
+                .collect()
+        })
+        .collect()
+}
+
+fn metadata() -> models::SongMetadata {
+    models::SongMetadata {
+        title: "insert benchmark".to_string(),
+        singer_id: 0,
+        date_first_sung: None,
+        local_path: None,
+        joined_paths: Vec::new(),
+    }
+}
+
+/// The csv copy `insert_new_song` did before switching to the binary format, a message per row
+/// with each vector formatted as text
+async fn insert_csv(conn: &mut PgConnection, frames: Vec<Vec<f32>>) -> Result<(), sqlx::Error> {
+    let mut tx = conn.begin().await?;
+    let metadata = metadata();
+    let (song_id,): (i64,) =
+        sqlx::query_as("insert into songs(title, singer_id) values ($1, $2) returning id")
+            .bind(&metadata.title)
+            .bind(metadata.singer_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+    let hop = FFT_SIZE - FFT_OVERLAP;
+    let mut copy_in = tx.copy_in_raw("copy segments(song_id, segment_index, vec, start_ts_ms, end_ts_ms) from stdin with (format csv, delimiter '|', header false)").await?;
+    for (index, segment) in frames.into_iter().enumerate() {
+        let start_time_ms = (index * hop * 1000 / SAMPLERATE) as i64;
+        let end_time_ms = ((index * hop + FFT_SIZE) * 1000 / SAMPLERATE) as i64;
+        copy_in
+            .send(
+                format!(
+                    "{song_id}|{index}|{}|{start_time_ms}|{end_time_ms}\n",
+                    format!("{segment:?}").replace(' ', "")
+                )
+                .as_bytes(),
+            )
+            .await?;
+    }
+    copy_in.finish().await?;
+
+    tx.rollback().await
+}
+
+fn insert(c: &mut Criterion) {
+    let Ok(url) = std::env::var("PLINK_BENCH_DATABASE_URL") else {
+        eprintln!("PLINK_BENCH_DATABASE_URL isn't set, skipping insert benchmarks");
+        return;
+    };
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let db = runtime
+        .block_on(Database::connect(&url))
+        .expect("failed to connect to database");
+    let mut conn = runtime
+        .block_on(PgConnection::connect(&url))
+        .expect("failed to connect to database");
+    let frames = frames();
+
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Elements(N_FRAMES as u64));
+    group.sample_size(20);
+
+    group.bench_function("binary", |b| {
+        b.to_async(&runtime).iter_batched(
+            || frames.clone(),
+            |frames| async {
+                let mut tx = db.transaction().await.unwrap();
+                tx.insert_new_song(frames, &metadata(), SAMPLERATE, FFT_SIZE, FFT_OVERLAP)
+                    .await
+                    .unwrap();
+                tx.rollback().await.unwrap();
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    // the async bencher's futures can't hold onto a borrow of the connection, so this blocks on
+    // each insert instead
+    group.bench_function("csv", |b| {
+        b.iter_batched(
+            || frames.clone(),
+            |frames| runtime.block_on(insert_csv(&mut conn, frames)).unwrap(),
+            BatchSize::LargeInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, insert);
+criterion_main!(benches);
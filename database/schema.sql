@@ -1,21 +1,31 @@
-create extension if not exists vector cascade;
+-- always in `public`, so every schema sees the `vector` type, see `DatabaseConfig::schema`
+create extension if not exists vector schema public cascade;
 
-create table singers (
+create table if not exists singers (
     id smallserial primary key,
     s_name varchar not null
 );
 
-create table songs (
+create table if not exists songs (
     id bigserial not null primary key,
     title varchar not null,
     singer_id smallint references singers(id),
     -- TODO: make this utc or something idk
     date_first_sung date,
     -- TODO: not sure if this is the best way to store this, feels a bit out-of-scope
-    local_path varchar
+    local_path varchar,
+    -- the paths of any files joined on after `local_path` when they were uploaded as one song
+    joined_paths varchar[] not null default '{}',
+    -- updated whenever the song is a discover result, used to prune songs that are never matched
+    last_matched_at timestamptz not null default now(),
+    -- the parameters the song's spectrogram was generated with, null for songs inserted before
+    -- these were recorded
+    samplerate integer,
+    fft_size integer,
+    fft_overlap integer
 );
 
-create table segments (
+create table if not exists segments (
     song_id bigint not null references songs(id),
     segment_index bigint not null,
     -- vector is size of fft output as each is a line of the spectrogram
@@ -29,6 +39,13 @@ create table segments (
     primary key (song_id, segment_index)
 );
 
+-- bring databases created before these columns existed up to date
+alter table songs add column if not exists last_matched_at timestamptz not null default now();
+alter table songs add column if not exists samplerate integer;
+alter table songs add column if not exists fft_size integer;
+alter table songs add column if not exists fft_overlap integer;
+alter table songs add column if not exists joined_paths varchar[] not null default '{}';
+
 -- for building index consider using (make larger if possible):
 -- SET max_parallel_maintenance_workers = 7;
 -- SET maintenance_work_mem = '10GB';
@@ -37,12 +54,15 @@ create table segments (
 
 -- furthermore, I would suggest initalizing the database *without* an index
 -- and adding the index once all of the vectors are inserted
-create index on segments using hnsw (vec vector_l2_ops);
+-- named the same as postgres names an unnamed index, so older databases aren't given a second one
+create index if not exists segments_vec_idx on segments using hnsw (vec vector_l2_ops);
 
 -- add known singers
 
-insert into singers(id, s_name) values (0, 'neuro v1');
-insert into singers(id, s_name) values (1, 'neuro v2');
-insert into singers(id, s_name) values (2, 'neuro v3');
-insert into singers(id, s_name) values (3, 'evil');
-insert into singers(id, s_name) values (4, 'duet');
+insert into singers(id, s_name) values (0, 'neuro v1') on conflict do nothing;
+insert into singers(id, s_name) values (1, 'neuro v2') on conflict do nothing;
+insert into singers(id, s_name) values (2, 'neuro v3') on conflict do nothing;
+insert into singers(id, s_name) values (3, 'evil') on conflict do nothing;
+insert into singers(id, s_name) values (4, 'duet') on conflict do nothing;
+-- inserting ids explicitly doesn't advance the sequence, so move it past them for new singers
+select setval(pg_get_serial_sequence('singers', 'id'), (select max(id) from singers));
@@ -0,0 +1,42 @@
+use std::fmt;
+
+/// Why inserting a song failed
+#[derive(Debug)]
+pub enum InsertError {
+    /// There is no song with this id to replace
+    SongNotFound {
+        song_id: i64,
+    },
+    /// A segment contains a `NaN` or infinite value, which pgvector can't store
+    NonFiniteSegment {
+        segment_index: i64,
+    },
+    Sqlx(sqlx::Error),
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SongNotFound { song_id } => write!(f, "song {song_id} doesn't exist"),
+            Self::NonFiniteSegment { segment_index } => {
+                write!(f, "segment {segment_index} contains a non-finite value")
+            }
+            Self::Sqlx(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for InsertError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::SongNotFound { .. } | Self::NonFiniteSegment { .. } => None,
+            Self::Sqlx(error) => Some(error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for InsertError {
+    fn from(error: sqlx::Error) -> Self {
+        Self::Sqlx(error)
+    }
+}
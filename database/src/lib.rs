@@ -1,9 +1,69 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr, time::Duration};
 
 use pgvector::Vector;
-use tracing::{debug, instrument};
 
+#[macro_use]
+mod methods;
+
+mod error;
+pub mod memory;
 pub mod models;
+mod queries;
+mod search;
+mod transaction;
+
+pub use error::InsertError;
+pub use search::SimilaritySearch;
+pub use transaction::DatabaseTx;
+
+/// Options used when connecting to the database
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    /// Reported to postgres as the connection's `application_name`, making it show up in
+    /// `pg_stat_activity`
+    pub application_name: Option<String>,
+    /// The most connections the pool will open at once
+    pub max_connections: u32,
+    /// How many connections the pool keeps open even when idle
+    pub min_connections: u32,
+    /// How long to wait for a free connection before giving up
+    pub acquire_timeout: Duration,
+    /// How long a connection can sit idle before being closed, `None` to never close them
+    pub idle_timeout: Option<Duration>,
+    /// Look for the tables in this postgres schema instead of `public`, by putting it first in
+    /// each connection's `search_path`
+    ///
+    /// The schema has to exist already, and connecting fails if it doesn't rather than quietly
+    /// falling back to the tables in `public`. This is spliced into sql, so it has to be a plain
+    /// identifier of letters, digits and underscores, and anything else is rejected when
+    /// connecting rather than risking sql injection.
+    pub schema: Option<String>,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            application_name: None,
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            schema: None,
+        }
+    }
+}
+
+/// Whether `name` can be used unescaped as a postgres identifier, ignoring keywords, which are
+/// fine once quoted
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    let starts_ok = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+
+    // postgres truncates identifiers to 63 bytes
+    starts_ok && name.len() <= 63 && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
 #[derive(Clone)]
 pub struct Database {
@@ -12,162 +72,125 @@ pub struct Database {
 
 impl Database {
     pub async fn connect(url: &str) -> Result<Self, sqlx::Error> {
-        let pool = sqlx::PgPool::connect(url).await?;
+        Self::connect_with_config(url, &DatabaseConfig::default()).await
+    }
+
+    pub async fn connect_with_config(
+        url: &str,
+        config: &DatabaseConfig,
+    ) -> Result<Self, sqlx::Error> {
+        let mut options = sqlx::postgres::PgConnectOptions::from_str(url)?;
+        if let Some(application_name) = &config.application_name {
+            options = options.application_name(application_name);
+        }
+
+        let mut pool_options = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(config.max_connections)
+            .min_connections(config.min_connections)
+            .acquire_timeout(config.acquire_timeout)
+            .idle_timeout(config.idle_timeout);
+        if let Some(schema) = &config.schema {
+            if !is_identifier(schema) {
+                return Err(sqlx::Error::Configuration(
+                    format!("`{schema}` isn't a valid schema name").into(),
+                ));
+            }
+
+            // `public` is kept so that the pgvector types are still found
+            let sql = format!("set search_path to \"{schema}\", public");
+            pool_options = pool_options.after_connect(move |conn, _| {
+                let sql = sql.clone();
+                Box::pin(async move {
+                    sqlx::Executor::execute(conn, sql.as_str()).await?;
+                    Ok(())
+                })
+            });
+        }
+
+        let pool = pool_options.connect_with(options).await?;
+
+        if let Some(schema) = &config.schema {
+            // postgres skips search path entries that don't exist, which would leave every
+            // query using the tables in `public`
+            let exists: bool =
+                sqlx::query_scalar("select exists(select 1 from pg_namespace where nspname = $1)")
+                    .bind(schema)
+                    .fetch_one(&pool)
+                    .await?;
+            if !exists {
+                pool.close().await;
+                return Err(sqlx::Error::Configuration(
+                    format!("schema `{schema}` doesn't exist").into(),
+                ));
+            }
+        }
 
         Ok(Self { pool })
     }
 
-    pub async fn find_similar_to(
-        &self,
-        vector: impl Into<Vector>,
-        thresh: f64,
-        limit: i64,
-    ) -> Result<Vec<(i64, i64, f64)>, sqlx::Error> {
-        let vector: Vector = vector.into();
-        let result: Vec<(i64, i64, f64)> = sqlx::query_as(
-            "
-            select song_id, segment_index, vec <-> $1 from segments
-            where vec <-> $1 < $2
-            order by vec <-> $1
-            limit $3
-            ",
-        )
-        .bind(vector)
-        .bind(thresh)
-        .bind(limit)
-        .fetch_all(&self.pool)
-        .await?;
-
-        Ok(result)
+    /// Start a transaction, everything done through the returned [`DatabaseTx`] is only
+    /// persisted once [`DatabaseTx::commit`] is called
+    pub async fn transaction(&self) -> Result<DatabaseTx<'_>, sqlx::Error> {
+        Ok(DatabaseTx::new(self.pool.begin().await?))
     }
 
-    #[instrument(skip(self, spectrogram), ret, level = "trace")]
-    pub async fn insert_new_song(
-        &self,
-        spectrogram: Vec<Vec<f32>>,
-        metadata: &models::SongMetadata,
-        samplerate: usize,
-        fft_size: usize,
-        fft_overlap: usize,
-    ) -> Result<i64, sqlx::Error> {
-        let (song_id,): (i64,) = sqlx::query_as(
-            "
-            insert into songs(title, singer_id, date_first_sung, local_path)
-            values ($1, $2, $3, $4)
-            returning id
-        ",
-        )
-        .bind(&metadata.title)
-        .bind(metadata.singer_id as i16)
-        .bind(metadata.date_first_sung)
-        .bind(&metadata.local_path)
-        .fetch_one(&self.pool)
-        .await?;
-
-        self.insert_sectrogram_for_song(song_id, spectrogram, samplerate, fft_size, fft_overlap)
-            .await?;
-
-        Ok(song_id)
+    /// Close every connection to the database, for shutting down cleanly when embedded in
+    /// something longer running than the cli
+    ///
+    /// This waits for connections that are in use to be given back, so any in-flight queries
+    /// and transactions should be finished first. As clones share the same pool, they're closed
+    /// too, and anything they try to do afterwards fails with [`sqlx::Error::PoolClosed`]
+    pub async fn close(self) {
+        self.pool.close().await
     }
 
-    #[instrument(skip(self, spectrogram), level = "trace")]
-    async fn insert_sectrogram_for_song(
-        &self,
-        song_id: i64,
-        spectrogram: Vec<Vec<f32>>,
-        samplerate: usize,
-        fft_size: usize,
-        fft_overlap: usize,
-    ) -> Result<(), sqlx::Error> {
-        let (segments,): (i64,) =
-            sqlx::query_as("select count(*) from segments where song_id = $1")
-                .bind(song_id)
-                .fetch_one(&self.pool)
-                .await?;
-
-        if segments > 0 {
-            panic!("song already exists, not inserting new values");
-        }
+    query_methods!((&self), self.pool.acquire().await?);
+}
 
-        let fft_offset = fft_size - fft_overlap;
-
-        let mut connection = self.pool.acquire().await?;
-        let mut copy_in = connection.copy_in_raw("copy segments(song_id, segment_index, vec, start_ts_ms, end_ts_ms) from stdin with (format csv, delimiter '|', header false)").await?;
-
-        for (index, segment) in spectrogram.into_iter().enumerate() {
-            let start_offset = fft_offset * index;
-            let start_time_ms = (start_offset as f64 * 1.0 / samplerate as f64 * 1000.0) as i64;
-            let end_offset = fft_offset * index + fft_size;
-            let end_time_ms = (end_offset as f64 * 1.0 / samplerate as f64 * 1000.0) as i64;
-            copy_in
-                .send(
-                    format!(
-                        "{song_id}|{index}|{}|{start_time_ms}|{end_time_ms}\n",
-                        format!("{segment:?}").replace(" ", "")
-                    )
-                    .as_bytes(),
-                )
-                .await?;
-        }
-        let rows_affected = copy_in.finish().await?;
-        debug!(n_rows = rows_affected, "affected rows");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    fn test_url() -> Option<String> {
+        let url = std::env::var("PLINK_TEST_DATABASE_URL").ok();
+        if url.is_none() {
+            eprintln!("PLINK_TEST_DATABASE_URL isn't set, skipping");
+        }
+        url
     }
 
-    pub async fn get_song(&self, song_id: i64) -> Result<Option<models::Song>, sqlx::Error> {
-        let results: Option<(i64, String, i16, Option<time::Date>, Option<String>)> =
-            sqlx::query_as(
-                "select id, title, singer_id, date_first_sung, local_path from songs where id = $1",
-            )
-            .bind(song_id)
-            .fetch_optional(&self.pool)
-            .await?;
-
-        let (_, title, singer_id, date_first_sung, local_path) = match results {
-            Some(r) => r,
-            None => return Ok(None),
-        };
-
-        Ok(Some(models::Song {
-            id: song_id,
-            metadata: models::SongMetadata {
-                title,
-                singer_id,
-                date_first_sung,
-                local_path,
-            },
-        }))
+    fn with_schema(schema: &str) -> DatabaseConfig {
+        DatabaseConfig {
+            schema: Some(schema.to_string()),
+            ..Default::default()
+        }
     }
 
-    pub async fn get_singers(&self) -> Result<HashMap<i16, models::Singer>, sqlx::Error> {
-        let results: Vec<(i16, String)> = sqlx::query_as("select id, s_name from singers")
-            .fetch_all(&self.pool)
-            .await?;
-        let singers = results
-            .into_iter()
-            .map(|(id, name)| models::Singer { id, name })
-            .collect::<Vec<_>>();
-
-        Ok(singers
-            .into_iter()
-            .map(|singer| (singer.id, singer))
-            .collect())
-    }
+    #[tokio::test]
+    async fn connecting_to_a_missing_schema_fails() {
+        let Some(url) = test_url() else {
+            return;
+        };
 
-    pub async fn song_already_saved(&self, full_file_path: &str) -> Result<bool, sqlx::Error> {
-        sqlx::query_as("select 1 from songs where local_path = $1")
-            .bind(full_file_path)
-            .fetch_optional(&self.pool)
+        let error = Database::connect_with_config(&url, &with_schema("plink_missing_schema"))
             .await
-            .map(|n: Option<(i32,)>| n.is_some())
+            .err()
+            .expect("connected to a schema that doesn't exist");
+        assert!(
+            error.to_string().contains("plink_missing_schema"),
+            "unexpected error {error}"
+        );
     }
 
-    pub async fn get_song_duration_ms(&self, song_id: i64) -> Result<Option<i64>, sqlx::Error> {
-        sqlx::query_as("select max(end_ts_ms) from segments where song_id = $1")
-            .bind(song_id)
-            .fetch_optional(&self.pool)
+    #[tokio::test]
+    async fn connecting_to_an_existing_schema_works() {
+        let Some(url) = test_url() else {
+            return;
+        };
+
+        let db = Database::connect_with_config(&url, &with_schema("public"))
             .await
-            .map(|ok| ok.map(|(v,): (i64,)| v))
+            .expect("failed to connect to the public schema");
+        db.close().await;
     }
 }
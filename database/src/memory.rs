@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, RwLock},
+};
+
+use crate::{models, search::SimilaritySearch};
+
+/// A brute-force [`SimilaritySearch`] over songs held in memory
+///
+/// Every query is compared against every stored segment using l2 distance, so this is only
+/// suitable for small sets of songs or for testing
+#[derive(Clone, Default)]
+pub struct InMemoryDatabase {
+    inner: Arc<RwLock<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    singers: HashMap<i16, models::Singer>,
+    songs: HashMap<i64, StoredSong>,
+    next_song_id: i64,
+}
+
+struct StoredSong {
+    song: models::Song,
+    segments: Vec<Vec<f32>>,
+    duration_ms: i64,
+}
+
+impl InMemoryDatabase {
+    pub fn insert_singer(&self, singer: models::Singer) {
+        let mut inner = self.inner.write().unwrap();
+        inner.singers.insert(singer.id, singer);
+    }
+
+    /// Store a song, returning its new id
+    pub fn insert_song(
+        &self,
+        metadata: models::SongMetadata,
+        segments: Vec<Vec<f32>>,
+        duration_ms: i64,
+    ) -> i64 {
+        let mut inner = self.inner.write().unwrap();
+        let id = inner.next_song_id;
+        inner.next_song_id += 1;
+        inner.songs.insert(
+            id,
+            StoredSong {
+                song: models::Song {
+                    id,
+                    metadata,
+                    params: None,
+                },
+                segments,
+                duration_ms,
+            },
+        );
+
+        id
+    }
+}
+
+impl SimilaritySearch for InMemoryDatabase {
+    type Error = Infallible;
+
+    async fn find_similar(
+        &self,
+        vector: Vec<f32>,
+        thresh: f64,
+        limit: i64,
+    ) -> Result<Vec<models::SimilarMatch>, Self::Error> {
+        let inner = self.inner.read().unwrap();
+        let mut matches = inner
+            .songs
+            .values()
+            .flat_map(|stored| {
+                stored.segments.iter().enumerate().map(|(index, segment)| {
+                    models::SimilarMatch {
+                        song_id: stored.song.id,
+                        segment_index: index as i64,
+                        distance: l2(&vector, segment),
+                        // segments are stored without their timestamps
+                        start_ts_ms: None,
+                    }
+                })
+            })
+            .filter(|found| found.distance < thresh)
+            .collect::<Vec<_>>();
+
+        matches.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        matches.truncate(limit.max(0) as usize);
+
+        Ok(matches)
+    }
+
+    async fn get_song(&self, song_id: i64) -> Result<Option<models::Song>, Self::Error> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner.songs.get(&song_id).map(|stored| stored.song.clone()))
+    }
+
+    async fn get_songs_bulk(
+        &self,
+        song_ids: &[i64],
+    ) -> Result<HashMap<i64, models::Song>, Self::Error> {
+        let inner = self.inner.read().unwrap();
+        Ok(song_ids
+            .iter()
+            .filter_map(|song_id| inner.songs.get(song_id))
+            .map(|stored| (stored.song.id, stored.song.clone()))
+            .collect())
+    }
+
+    async fn get_singers(&self) -> Result<HashMap<i16, models::Singer>, Self::Error> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner.singers.clone())
+    }
+
+    async fn get_segment_stats_bulk(
+        &self,
+        song_ids: &[i64],
+    ) -> Result<HashMap<i64, models::SegmentStats>, Self::Error> {
+        let inner = self.inner.read().unwrap();
+        Ok(song_ids
+            .iter()
+            .filter_map(|song_id| inner.songs.get(song_id))
+            .filter(|stored| !stored.segments.is_empty())
+            .map(|stored| {
+                let stats = models::SegmentStats {
+                    duration_ms: stored.duration_ms,
+                    segment_count: stored.segments.len() as i64,
+                };
+                (stored.song.id, stats)
+            })
+            .collect())
+    }
+
+    async fn touch_songs(&self, _song_ids: &[i64]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+fn l2(a: &[f32], b: &[f32]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
@@ -0,0 +1,457 @@
+//! The query methods shared by [`crate::Database`] and [`crate::DatabaseTx`]
+
+/// Defines every query method on the type it's expanded in, with `$receiver` as their receiver
+/// and `$conn` giving the connection each query runs on
+///
+/// The only difference between the two is where the connection comes from, a fresh one out of
+/// the pool for [`crate::Database`] and the open transaction for [`crate::DatabaseTx`], so this
+/// keeps them from drifting apart as queries are added
+macro_rules! query_methods {
+    (($($receiver:tt)+), $conn:expr) => {
+        /// Create the pgvector extension, tables and index if they don't already exist, and add any
+        /// columns missing from databases made by older versions
+        pub async fn ensure_schema($($receiver)+) -> Result<(), sqlx::Error> {
+            let mut conn = $conn;
+            queries::ensure_schema(&mut conn).await
+        }
+
+        pub async fn find_similar_to(
+            $($receiver)+,
+            vector: impl Into<Vector>,
+            thresh: f64,
+            limit: i64,
+        ) -> Result<Vec<models::SimilarMatch>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::find_similar_to(
+                &mut conn,
+                vector.into(),
+                thresh,
+                limit,
+                models::DistanceMetric::L2,
+            )
+            .await
+        }
+
+        /// Like [`Self::find_similar_to`], but measuring distance with `metric`, which is also what
+        /// the returned distances and `thresh` are in terms of
+        pub async fn find_similar_to_with_metric(
+            $($receiver)+,
+            vector: impl Into<Vector>,
+            thresh: f64,
+            limit: i64,
+            metric: models::DistanceMetric,
+        ) -> Result<Vec<models::SimilarMatch>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::find_similar_to(&mut conn, vector.into(), thresh, limit, metric).await
+        }
+
+        /// Like [`Self::find_similar_to`], but with at most `per_song` of the results from any one
+        /// song, so a near duplicate recording can't take up every result
+        pub async fn find_similar_to_per_song(
+            $($receiver)+,
+            vector: impl Into<Vector>,
+            thresh: f64,
+            limit: i64,
+            per_song: i64,
+        ) -> Result<Vec<models::SimilarMatch>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::find_similar_to_per_song(
+                &mut conn,
+                vector.into(),
+                thresh,
+                limit,
+                per_song,
+                models::DistanceMetric::L2,
+            )
+            .await
+        }
+
+        /// Like [`Self::find_similar_to`] for many vectors in a single query, returning the matches
+        /// for each vector in the same order as `vectors`
+        pub async fn find_similar_to_batch(
+            $($receiver)+,
+            vectors: &[Vector],
+            thresh: f64,
+            limit_per: i64,
+        ) -> Result<Vec<Vec<models::SimilarMatch>>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::find_similar_to_batch(
+                &mut conn,
+                vectors,
+                thresh,
+                limit_per,
+                models::DistanceMetric::L2,
+            )
+            .await
+        }
+
+        pub async fn insert_new_song(
+            $($receiver)+,
+            spectrogram: Vec<Vec<f32>>,
+            metadata: &models::SongMetadata,
+            samplerate: usize,
+            fft_size: usize,
+            fft_overlap: usize,
+        ) -> Result<models::InsertResult, InsertError> {
+            let frame_starts =
+                queries::evenly_spaced_starts(spectrogram.len(), fft_size, fft_overlap);
+            let segments = queries::contiguous_segments(spectrogram, &frame_starts);
+            let mut conn = $conn;
+            queries::insert_new_song(
+                &mut conn,
+                segments.into_iter(),
+                metadata,
+                samplerate,
+                fft_size,
+                fft_overlap,
+            )
+            .await
+        }
+
+        /// Like [`Self::insert_new_song`], but for frames that aren't evenly spaced, with
+        /// `frame_starts` holding the offset in samples of the start of each frame
+        pub async fn insert_new_song_at(
+            $($receiver)+,
+            spectrogram: Vec<Vec<f32>>,
+            metadata: &models::SongMetadata,
+            samplerate: usize,
+            fft_size: usize,
+            fft_overlap: usize,
+            frame_starts: &[usize],
+        ) -> Result<models::InsertResult, InsertError> {
+            let segments = queries::contiguous_segments(spectrogram, frame_starts);
+            let mut conn = $conn;
+            queries::insert_new_song(
+                &mut conn,
+                segments.into_iter(),
+                metadata,
+                samplerate,
+                fft_size,
+                fft_overlap,
+            )
+            .await
+        }
+
+        /// Like [`Self::insert_new_song`], but copying each frame into the database as soon as
+        /// `spectrogram` produces it, e.g. from [`process::SpectrogramGenerator::run_streaming`],
+        /// so the whole spectrogram is never held in memory
+        pub async fn insert_new_song_streaming(
+            $($receiver)+,
+            spectrogram: impl Iterator<Item = Vec<f32>> + Send,
+            metadata: &models::SongMetadata,
+            samplerate: usize,
+            fft_size: usize,
+            fft_overlap: usize,
+        ) -> Result<models::InsertResult, InsertError> {
+            let segments = queries::streamed_segments(spectrogram, fft_size, fft_overlap);
+            let mut conn = $conn;
+            queries::insert_new_song(
+                &mut conn,
+                segments,
+                metadata,
+                samplerate,
+                fft_size,
+                fft_overlap,
+            )
+            .await
+        }
+
+        /// Like [`Self::insert_new_song`], but for spectrograms that had frames skipped, with each
+        /// segment's index and start offset given explicitly
+        pub async fn insert_new_song_segments(
+            $($receiver)+,
+            segments: Vec<models::NewSegment>,
+            metadata: &models::SongMetadata,
+            samplerate: usize,
+            fft_size: usize,
+            fft_overlap: usize,
+        ) -> Result<models::InsertResult, InsertError> {
+            let mut conn = $conn;
+            queries::insert_new_song(
+                &mut conn,
+                segments.into_iter(),
+                metadata,
+                samplerate,
+                fft_size,
+                fft_overlap,
+            )
+            .await
+        }
+
+        /// Replace the metadata and fingerprint of an existing song in a single transaction,
+        /// keeping its id, e.g. for when its audio file has changed
+        pub async fn replace_song(
+            $($receiver)+,
+            song_id: i64,
+            spectrogram: Vec<Vec<f32>>,
+            metadata: &models::SongMetadata,
+            samplerate: usize,
+            fft_size: usize,
+            fft_overlap: usize,
+        ) -> Result<models::InsertResult, InsertError> {
+            let frame_starts =
+                queries::evenly_spaced_starts(spectrogram.len(), fft_size, fft_overlap);
+            let segments = queries::contiguous_segments(spectrogram, &frame_starts);
+            let mut conn = $conn;
+            queries::replace_song(
+                &mut conn,
+                song_id,
+                segments.into_iter(),
+                metadata,
+                samplerate,
+                fft_size,
+                fft_overlap,
+            )
+            .await
+        }
+
+        /// Like [`Self::replace_song`], but for frames that aren't evenly spaced, see
+        /// [`Self::insert_new_song_at`]
+        #[allow(clippy::too_many_arguments)]
+        pub async fn replace_song_at(
+            $($receiver)+,
+            song_id: i64,
+            spectrogram: Vec<Vec<f32>>,
+            metadata: &models::SongMetadata,
+            samplerate: usize,
+            fft_size: usize,
+            fft_overlap: usize,
+            frame_starts: &[usize],
+        ) -> Result<models::InsertResult, InsertError> {
+            let segments = queries::contiguous_segments(spectrogram, frame_starts);
+            let mut conn = $conn;
+            queries::replace_song(
+                &mut conn,
+                song_id,
+                segments.into_iter(),
+                metadata,
+                samplerate,
+                fft_size,
+                fft_overlap,
+            )
+            .await
+        }
+
+        pub async fn get_song(
+            $($receiver)+,
+            song_id: i64,
+        ) -> Result<Option<models::Song>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_song(&mut conn, song_id).await
+        }
+
+        /// Every song out of `song_ids` that exists, keyed by id
+        pub async fn get_songs_bulk(
+            $($receiver)+,
+            song_ids: &[i64],
+        ) -> Result<HashMap<i64, models::Song>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_songs_bulk(&mut conn, song_ids).await
+        }
+
+        /// Up to `limit` songs ordered by id, skipping the first `offset`
+        pub async fn get_songs_paginated(
+            $($receiver)+,
+            limit: i64,
+            offset: i64,
+        ) -> Result<Vec<models::Song>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_songs_paginated(&mut conn, limit, offset).await
+        }
+
+        /// Up to `limit` songs whose title contains `query`, ignoring case, ordered by id
+        pub async fn search_songs_by_title(
+            $($receiver)+,
+            query: &str,
+            limit: i64,
+        ) -> Result<Vec<models::Song>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::search_songs_by_title(&mut conn, query, limit).await
+        }
+
+        /// Every song without a `date_first_sung`, in order of id
+        pub async fn get_songs_missing_date(
+            $($receiver)+,
+        ) -> Result<Vec<models::Song>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_songs_missing_date(&mut conn).await
+        }
+
+        /// The number of songs that have no stored segments, such as from an interrupted upload,
+        /// which can never be matched
+        pub async fn count_songs_without_segments($($receiver)+) -> Result<i64, sqlx::Error> {
+            let mut conn = $conn;
+            queries::count_songs_without_segments(&mut conn).await
+        }
+
+        /// The total number of songs, for use with [`Self::get_songs_paginated`]
+        pub async fn count_songs($($receiver)+) -> Result<i64, sqlx::Error> {
+            let mut conn = $conn;
+            queries::count_songs(&mut conn).await
+        }
+
+        /// The exact number of stored segments, this scans the whole table so can be slow once
+        /// there are a lot of songs
+        pub async fn count_segments($($receiver)+) -> Result<i64, sqlx::Error> {
+            let mut conn = $conn;
+            queries::count_segments(&mut conn).await
+        }
+
+        /// An approximate number of stored segments from the planner's statistics, which is much
+        /// faster than [`Self::count_segments`] on large tables
+        ///
+        /// This is `None` if the table hasn't been vacuumed or analyzed yet
+        pub async fn estimated_segment_count($($receiver)+) -> Result<Option<i64>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::estimated_segment_count(&mut conn).await
+        }
+
+        /// The stored vectors of every segment of a song, in order
+        pub async fn get_song_spectrogram(
+            $($receiver)+,
+            song_id: i64,
+        ) -> Result<Vec<Vec<f32>>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_song_spectrogram(&mut conn, song_id).await
+        }
+
+        /// Every stored segment of a song, ordered by their index
+        pub async fn get_song_segments(
+            $($receiver)+,
+            song_id: i64,
+        ) -> Result<Vec<models::Sample>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_song_segments(&mut conn, song_id).await
+        }
+
+        pub async fn get_singers(
+            $($receiver)+,
+        ) -> Result<HashMap<i16, models::Singer>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_singers(&mut conn).await
+        }
+
+        /// The number of songs each singer has, singers without any songs are left out
+        pub async fn song_count_by_singer($($receiver)+) -> Result<HashMap<i16, i64>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::song_count_by_singer(&mut conn).await
+        }
+
+        pub async fn get_singer(
+            $($receiver)+,
+            singer_id: i16,
+        ) -> Result<Option<models::Singer>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_singer(&mut conn, singer_id).await
+        }
+
+        /// Add a new singer, returning their id
+        ///
+        /// Databases made before new singers could be added need [`crate::Database::ensure_schema`]
+        /// (the cli's `migrate`) run first, which moves the id sequence past the built in singers,
+        /// as otherwise this tries to reuse their ids and fails
+        pub async fn create_singer($($receiver)+, name: &str) -> Result<i16, sqlx::Error> {
+            let mut conn = $conn;
+            queries::create_singer(&mut conn, name).await
+        }
+
+        /// Replace the metadata of a song without touching its segments, returning `false` if there
+        /// is no song with that id
+        pub async fn update_song_metadata(
+            $($receiver)+,
+            song_id: i64,
+            metadata: &models::SongMetadata,
+        ) -> Result<bool, sqlx::Error> {
+            let mut conn = $conn;
+            queries::update_song_metadata(&mut conn, song_id, metadata).await
+        }
+
+        /// Change the name of a singer, returning `false` if there is no singer with that id
+        pub async fn rename_singer(
+            $($receiver)+,
+            singer_id: i16,
+            name: &str,
+        ) -> Result<bool, sqlx::Error> {
+            let mut conn = $conn;
+            queries::rename_singer(&mut conn, singer_id, name).await
+        }
+
+        /// The song uploaded from `full_file_path`, either on its own or as one of the files
+        /// joined to make it up, see [`Self::song_already_saved`]
+        pub async fn get_song_by_path(
+            $($receiver)+,
+            full_file_path: &str,
+        ) -> Result<Option<models::Song>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_song_by_path(&mut conn, full_file_path).await
+        }
+
+        /// Whether any song was uploaded from `full_file_path`, including as a joined file
+        pub async fn song_already_saved(
+            $($receiver)+,
+            full_file_path: &str,
+        ) -> Result<bool, sqlx::Error> {
+            let mut conn = $conn;
+            queries::song_already_saved(&mut conn, full_file_path).await
+        }
+
+        pub async fn get_song_duration_ms(
+            $($receiver)+,
+            song_id: i64,
+        ) -> Result<Option<i64>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_song_duration_ms(&mut conn, song_id).await
+        }
+
+        /// How many segments are stored for a song, which is 0 if it doesn't exist
+        pub async fn get_song_segment_count(
+            $($receiver)+,
+            song_id: i64,
+        ) -> Result<i64, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_song_segment_count(&mut conn, song_id).await
+        }
+
+        /// The duration and segment count of every song out of `song_ids` that has any segments,
+        /// keyed by id, in a single query
+        pub async fn get_segment_stats_bulk(
+            $($receiver)+,
+            song_ids: &[i64],
+        ) -> Result<HashMap<i64, models::SegmentStats>, sqlx::Error> {
+            let mut conn = $conn;
+            queries::get_segment_stats_bulk(&mut conn, song_ids).await
+        }
+
+        /// Mark a song as having just been matched, so it isn't pruned by [`Self::prune_songs`]
+        pub async fn touch_song($($receiver)+, song_id: i64) -> Result<(), sqlx::Error> {
+            let mut conn = $conn;
+            queries::touch_song(&mut conn, song_id).await
+        }
+
+        /// Like [`Self::touch_song`] for every song out of `song_ids`, in a single query
+        pub async fn touch_songs($($receiver)+, song_ids: &[i64]) -> Result<(), sqlx::Error> {
+            let mut conn = $conn;
+            queries::touch_songs(&mut conn, song_ids).await
+        }
+
+        /// Delete every song (and its segments) that hasn't been matched within `older_than`,
+        /// returning the number of songs deleted
+        pub async fn prune_songs(
+            $($receiver)+,
+            older_than: std::time::Duration,
+        ) -> Result<u64, sqlx::Error> {
+            let mut conn = $conn;
+            queries::prune_songs(&mut conn, older_than).await
+        }
+
+        /// Delete every song (and its segments) sung by `singer_id`, returning the number of songs
+        /// deleted
+        pub async fn delete_songs_by_singer(
+            $($receiver)+,
+            singer_id: i16,
+        ) -> Result<u64, sqlx::Error> {
+            let mut conn = $conn;
+            queries::delete_songs_by_singer(&mut conn, singer_id).await
+        }
+    };
+}
@@ -1,27 +1,106 @@
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Singer {
     pub id: i16,
     pub name: String,
 }
 
-#[derive(Debug)]
-
+#[derive(Debug, Clone)]
 pub struct Song {
     pub id: i64,
     pub metadata: SongMetadata,
+    /// The parameters the song's spectrogram was generated with, `None` for songs inserted
+    /// before these were recorded
+    pub params: Option<SpectrogramParams>,
+}
+
+/// The parameters a spectrogram was generated with, spectrograms are only comparable if these
+/// match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpectrogramParams {
+    pub samplerate: i32,
+    pub fft_size: i32,
+    /// For frames that aren't evenly spaced, this is the smallest overlap between frames
+    pub fft_overlap: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SongMetadata {
     pub title: String,
     pub singer_id: i16,
     pub date_first_sung: Option<time::Date>,
     pub local_path: Option<String>,
+    /// The paths of any files joined onto the end of `local_path` to make up this song, in order
+    pub joined_paths: Vec<String>,
+}
+
+/// A segment to be inserted
+#[derive(Debug, Clone)]
+pub struct NewSegment {
+    /// The index of this segment's frame in the full spectrogram, counting any frames that were
+    /// skipped, so gaps in the stored indices show where frames were skipped
+    pub segment_index: i64,
+    /// The offset in samples of the start of this segment's frame
+    pub start_offset: usize,
+    pub vector: Vec<f32>,
 }
 
-#[derive(Debug)]
+/// A stored segment of a song
+#[derive(Debug, Clone)]
 pub struct Sample {
-    pub song_id: u64,
-    pub sample_index: u32,
+    pub song_id: i64,
+    /// The segment's `segment_index`, which can have gaps where frames were skipped
+    pub sample_index: i64,
     pub sample: Vec<f32>,
+    pub start_ts_ms: i64,
+    pub end_ts_ms: i64,
+}
+
+/// A stored segment found within the threshold of a query vector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimilarMatch {
+    pub song_id: i64,
+    /// The segment's `segment_index`, see [`Sample::sample_index`]
+    pub segment_index: i64,
+    pub distance: f64,
+    /// When the segment starts in its song, if where it came from keeps track of that
+    pub start_ts_ms: Option<i64>,
+}
+
+/// How much of a song is stored, see [`crate::Database::get_segment_stats_bulk`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentStats {
+    /// The end timestamp of the song's last segment
+    pub duration_ms: i64,
+    pub segment_count: i64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct InsertResult {
+    pub song_id: i64,
+    /// The end timestamp of the last inserted segment
+    pub duration_ms: i64,
+    pub segment_count: u64,
+}
+
+/// How the distance between two segments is measured when searching
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceMetric {
+    /// Euclidean distance
+    #[default]
+    L2,
+    /// `1 - cosine similarity`, which ignores the overall loudness of a segment
+    Cosine,
+    /// The negated inner product, so that smaller is still closer
+    InnerProduct,
+}
+
+impl DistanceMetric {
+    /// The pgvector operator for this metric
+    pub(crate) fn operator(self) -> &'static str {
+        match self {
+            Self::L2 => "<->",
+            Self::Cosine => "<=>",
+            Self::InnerProduct => "<#>",
+        }
+    }
 }
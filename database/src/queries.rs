@@ -0,0 +1,1136 @@
+//! The queries behind both [`crate::Database`] and [`crate::DatabaseTx`]
+//!
+//! Everything here runs on a single connection, so can be used with either a connection taken
+//! from the pool or an open transaction
+
+use std::collections::HashMap;
+
+use pgvector::Vector;
+use sqlx::{Connection, Executor, PgConnection};
+use tracing::{debug, instrument};
+
+use crate::{models, InsertError};
+
+type SongRow = (
+    i64,
+    String,
+    i16,
+    Option<time::Date>,
+    Option<String>,
+    Vec<String>,
+    Option<i32>,
+    Option<i32>,
+    Option<i32>,
+);
+const SONG_COLUMNS: &str = "id, title, singer_id, date_first_sung, local_path, joined_paths, \
+                            samplerate, fft_size, fft_overlap";
+
+/// `song_id, segment_index, distance, start_ts_ms`
+type MatchRow = (i64, i64, f64, i64);
+
+fn similar_match(
+    (song_id, segment_index, distance, start_ts_ms): MatchRow,
+) -> models::SimilarMatch {
+    models::SimilarMatch {
+        song_id,
+        segment_index,
+        distance,
+        start_ts_ms: Some(start_ts_ms),
+    }
+}
+
+/// Every statement in `schema.sql` is safe to rerun, creating anything that's missing
+const SCHEMA: &str = include_str!("../schema.sql");
+
+pub(crate) async fn ensure_schema(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    // without any arguments this is sent as a simple query, which can hold many statements
+    let mut tx = conn.begin().await?;
+    (&mut *tx).execute(SCHEMA).await?;
+    tx.commit().await
+}
+
+/// The query behind [`find_similar_to`], taking the vector, threshold and limit
+fn find_similar_sql(metric: models::DistanceMetric) -> String {
+    // the index can only be used for an `order by` on the distance followed by a `limit`, so the
+    // threshold is applied afterwards, to the nearest segments
+    let op = metric.operator();
+    format!(
+        "
+        select song_id, segment_index, distance, start_ts_ms from (
+            select song_id, segment_index, vec {op} $1 as distance, start_ts_ms from segments
+            order by distance
+            limit $3
+        ) as nearest
+        where distance < $2
+        order by distance
+        "
+    )
+}
+
+pub(crate) async fn find_similar_to(
+    conn: &mut PgConnection,
+    vector: Vector,
+    thresh: f64,
+    limit: i64,
+    metric: models::DistanceMetric,
+) -> Result<Vec<models::SimilarMatch>, sqlx::Error> {
+    let rows: Vec<MatchRow> = sqlx::query_as(&find_similar_sql(metric))
+        .bind(vector)
+        .bind(thresh)
+        .bind(limit)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows.into_iter().map(similar_match).collect())
+}
+
+/// How many times more segments than the limit are taken from the index for
+/// [`find_similar_to_per_song`], so that there are still enough left once each song is capped
+const PER_SONG_OVERFETCH: i64 = 4;
+
+/// Like [`find_similar_to`], but with at most `per_song` of the results from any one song
+pub(crate) async fn find_similar_to_per_song(
+    conn: &mut PgConnection,
+    vector: Vector,
+    thresh: f64,
+    limit: i64,
+    per_song: i64,
+    metric: models::DistanceMetric,
+) -> Result<Vec<models::SimilarMatch>, sqlx::Error> {
+    // as with `find_similar_to` the index only helps with the innermost query, so more segments
+    // than needed are fetched there and then capped per song
+    let op = metric.operator();
+    let sql = format!(
+        "
+        select song_id, segment_index, distance, start_ts_ms from (
+            select
+                song_id,
+                segment_index,
+                distance,
+                start_ts_ms,
+                row_number() over (partition by song_id order by distance) as song_rank
+            from (
+                select song_id, segment_index, vec {op} $1 as distance, start_ts_ms from segments
+                order by distance
+                limit $4
+            ) as nearest
+            where distance < $2
+        ) as ranked
+        where song_rank <= $5
+        order by distance
+        limit $3
+        "
+    );
+    let rows: Vec<MatchRow> = sqlx::query_as(&sql)
+        .bind(vector)
+        .bind(thresh)
+        .bind(limit)
+        .bind(limit.saturating_mul(PER_SONG_OVERFETCH))
+        .bind(per_song)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(rows.into_iter().map(similar_match).collect())
+}
+
+/// Like [`find_similar_to`] for each of `vectors` at once, with the results for each vector in the
+/// same order as `vectors`
+pub(crate) async fn find_similar_to_batch(
+    conn: &mut PgConnection,
+    vectors: &[Vector],
+    thresh: f64,
+    limit_per: i64,
+    metric: models::DistanceMetric,
+) -> Result<Vec<Vec<models::SimilarMatch>>, sqlx::Error> {
+    let op = metric.operator();
+    let sql = format!(
+        "
+        select query.ord, nearest.song_id, nearest.segment_index, nearest.distance, nearest.start_ts_ms
+        from unnest($1::vector[]) with ordinality as query(vec, ord)
+        cross join lateral (
+            select song_id, segment_index, vec {op} query.vec as distance, start_ts_ms from segments
+            order by distance
+            limit $3
+        ) as nearest
+        where nearest.distance < $2
+        order by query.ord, nearest.distance
+        "
+    );
+    let rows: Vec<(i64, i64, i64, f64, i64)> = sqlx::query_as(&sql)
+        .bind(vectors)
+        .bind(thresh)
+        .bind(limit_per)
+        .fetch_all(conn)
+        .await?;
+
+    let mut results = vec![Vec::new(); vectors.len()];
+    for (ord, song_id, segment_index, distance, start_ts_ms) in rows {
+        // `with ordinality` counts from 1
+        results[ord as usize - 1].push(similar_match((
+            song_id,
+            segment_index,
+            distance,
+            start_ts_ms,
+        )));
+    }
+
+    Ok(results)
+}
+
+#[instrument(skip(conn, segments), ret, level = "trace")]
+pub(crate) async fn insert_new_song(
+    conn: &mut PgConnection,
+    segments: impl Iterator<Item = models::NewSegment> + Send,
+    metadata: &models::SongMetadata,
+    samplerate: usize,
+    fft_size: usize,
+    fft_overlap: usize,
+) -> Result<models::InsertResult, InsertError> {
+    // so that a failed copy doesn't leave behind a song with no segments
+    let mut tx = conn.begin().await?;
+
+    let (song_id,): (i64,) = sqlx::query_as(
+        "
+        insert into songs(title, singer_id, date_first_sung, local_path, joined_paths, samplerate, fft_size, fft_overlap)
+        values ($1, $2, $3, $4, $5, $6, $7, $8)
+        returning id
+    ",
+    )
+    .bind(&metadata.title)
+    .bind(metadata.singer_id)
+    .bind(metadata.date_first_sung)
+    .bind(&metadata.local_path)
+    .bind(&metadata.joined_paths)
+    .bind(samplerate as i32)
+    .bind(fft_size as i32)
+    .bind(fft_overlap as i32)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let (duration_ms, segment_count) =
+        insert_sectrogram_for_song(&mut tx, song_id, segments, samplerate, fft_size).await?;
+
+    tx.commit().await?;
+
+    Ok(models::InsertResult {
+        song_id,
+        duration_ms,
+        segment_count,
+    })
+}
+
+#[instrument(skip(conn, segments), level = "trace")]
+/// Replace the metadata and segments of an existing song, keeping its id
+pub(crate) async fn replace_song(
+    conn: &mut PgConnection,
+    song_id: i64,
+    segments: impl Iterator<Item = models::NewSegment> + Send,
+    metadata: &models::SongMetadata,
+    samplerate: usize,
+    fft_size: usize,
+    fft_overlap: usize,
+) -> Result<models::InsertResult, InsertError> {
+    let mut tx = conn.begin().await?;
+
+    let updated = sqlx::query(
+        "
+        update songs
+        set title = $2, singer_id = $3, date_first_sung = $4, local_path = $5, joined_paths = $6, samplerate = $7, fft_size = $8, fft_overlap = $9
+        where id = $1
+    ",
+    )
+    .bind(song_id)
+    .bind(&metadata.title)
+    .bind(metadata.singer_id)
+    .bind(metadata.date_first_sung)
+    .bind(&metadata.local_path)
+    .bind(&metadata.joined_paths)
+    .bind(samplerate as i32)
+    .bind(fft_size as i32)
+    .bind(fft_overlap as i32)
+    .execute(&mut *tx)
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        return Err(InsertError::SongNotFound { song_id });
+    }
+
+    sqlx::query("delete from segments where song_id = $1")
+        .bind(song_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let (duration_ms, segment_count) =
+        insert_sectrogram_for_song(&mut tx, song_id, segments, samplerate, fft_size).await?;
+
+    tx.commit().await?;
+
+    Ok(models::InsertResult {
+        song_id,
+        duration_ms,
+        segment_count,
+    })
+}
+
+/// Copy `segments` into the database as they're produced, so the whole spectrogram never has to
+/// be held in memory alongside the copy buffer
+async fn insert_sectrogram_for_song(
+    conn: &mut PgConnection,
+    song_id: i64,
+    segments: impl Iterator<Item = models::NewSegment> + Send,
+    samplerate: usize,
+    fft_size: usize,
+) -> Result<(i64, u64), InsertError> {
+    let mut duration_ms = 0;
+    let mut copy_in = conn.copy_in_raw("copy segments(song_id, segment_index, vec, start_ts_ms, end_ts_ms) from stdin with (format binary)").await?;
+
+    let mut buf = Vec::with_capacity(COPY_CHUNK_SIZE);
+    buf.extend_from_slice(COPY_BINARY_HEADER);
+    // flags, then the length of the header extension area
+    buf.extend_from_slice(&0_i32.to_be_bytes());
+    buf.extend_from_slice(&0_i32.to_be_bytes());
+
+    for models::NewSegment {
+        segment_index: index,
+        start_offset,
+        vector: segment,
+    } in segments
+    {
+        // this aborts the copy, and the caller's transaction then rolls back the song
+        if segment.iter().any(|value| !value.is_finite()) {
+            copy_in.abort("segment has non-finite values").await?;
+            return Err(InsertError::NonFiniteSegment {
+                segment_index: index,
+            });
+        }
+
+        let start_time_ms = (start_offset as f64 * 1.0 / samplerate as f64 * 1000.0) as i64;
+        let end_offset = start_offset + fft_size;
+        let end_time_ms = (end_offset as f64 * 1.0 / samplerate as f64 * 1000.0) as i64;
+        duration_ms = duration_ms.max(end_time_ms);
+
+        buf.extend_from_slice(&5_i16.to_be_bytes());
+        write_copy_i64(&mut buf, song_id);
+        write_copy_i64(&mut buf, index);
+        write_copy_vector(&mut buf, &segment);
+        write_copy_i64(&mut buf, start_time_ms);
+        write_copy_i64(&mut buf, end_time_ms);
+
+        if buf.len() >= COPY_CHUNK_SIZE {
+            copy_in.send(std::mem::take(&mut buf)).await?;
+        }
+    }
+    buf.extend_from_slice(&(-1_i16).to_be_bytes());
+    copy_in.send(buf).await?;
+    let rows_affected = copy_in.finish().await?;
+    debug!(n_rows = rows_affected, "affected rows");
+
+    Ok((duration_ms, rows_affected))
+}
+
+/// The signature at the start of every binary copy, see
+/// https://www.postgresql.org/docs/current/sql-copy.html#id-1.9.3.55.9.4
+const COPY_BINARY_HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0";
+/// Roughly how many bytes are buffered before being sent to postgres during a copy
+const COPY_CHUNK_SIZE: usize = 1 << 16;
+
+fn write_copy_i64(buf: &mut Vec<u8>, value: i64) {
+    buf.extend_from_slice(&8_i32.to_be_bytes());
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Write a vector in pgvector's binary format, a `u16` dimension and an unused `u16` followed by
+/// each value
+fn write_copy_vector(buf: &mut Vec<u8>, vector: &[f32]) {
+    let dim = u16::try_from(vector.len()).expect("vector has too many dimensions");
+    buf.extend_from_slice(&(4 + 4 * vector.len() as i32).to_be_bytes());
+    buf.extend_from_slice(&dim.to_be_bytes());
+    buf.extend_from_slice(&0_u16.to_be_bytes());
+    for value in vector {
+        buf.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// The start offsets of frames spaced [`process::SpectrogramConfig::hop`] apart
+pub(crate) fn evenly_spaced_starts(
+    n_frames: usize,
+    fft_size: usize,
+    fft_overlap: usize,
+) -> Vec<usize> {
+    let hop = process::SpectrogramConfig {
+        fft_len: fft_size,
+        overlap: fft_overlap,
+        ..Default::default()
+    }
+    .hop();
+    (0..n_frames).map(|index| index * hop).collect()
+}
+
+/// Pair each frame of a spectrogram with no skipped frames up with its index and start offset
+pub(crate) fn contiguous_segments(
+    spectrogram: Vec<Vec<f32>>,
+    frame_starts: &[usize],
+) -> Vec<models::NewSegment> {
+    spectrogram
+        .into_iter()
+        .zip(frame_starts)
+        .enumerate()
+        .map(|(index, (vector, start_offset))| models::NewSegment {
+            segment_index: index as i64,
+            start_offset: *start_offset,
+            vector,
+        })
+        .collect()
+}
+
+/// Like [`contiguous_segments`], but lazily for evenly spaced frames, working each frame's start
+/// offset out from its index
+pub(crate) fn streamed_segments(
+    frames: impl Iterator<Item = Vec<f32>> + Send,
+    fft_size: usize,
+    fft_overlap: usize,
+) -> impl Iterator<Item = models::NewSegment> + Send {
+    let hop = process::SpectrogramConfig {
+        fft_len: fft_size,
+        overlap: fft_overlap,
+        ..Default::default()
+    }
+    .hop();
+    frames
+        .enumerate()
+        .map(move |(index, vector)| models::NewSegment {
+            segment_index: index as i64,
+            start_offset: index * hop,
+            vector,
+        })
+}
+
+pub(crate) async fn get_song(
+    conn: &mut PgConnection,
+    song_id: i64,
+) -> Result<Option<models::Song>, sqlx::Error> {
+    let sql = format!("select {SONG_COLUMNS} from songs where id = $1");
+    let results: Option<SongRow> = sqlx::query_as(&sql)
+        .bind(song_id)
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(results.map(song_from_row))
+}
+
+pub(crate) async fn get_songs_bulk(
+    conn: &mut PgConnection,
+    song_ids: &[i64],
+) -> Result<HashMap<i64, models::Song>, sqlx::Error> {
+    let sql = format!("select {SONG_COLUMNS} from songs where id = any($1)");
+    let results: Vec<SongRow> = sqlx::query_as(&sql).bind(song_ids).fetch_all(conn).await?;
+
+    Ok(results
+        .into_iter()
+        .map(song_from_row)
+        .map(|song| (song.id, song))
+        .collect())
+}
+
+pub(crate) async fn get_songs_paginated(
+    conn: &mut PgConnection,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<models::Song>, sqlx::Error> {
+    let sql = format!(
+        "
+        select {SONG_COLUMNS} from songs
+        order by id
+        limit $1 offset $2
+        "
+    );
+    let results: Vec<SongRow> = sqlx::query_as(&sql)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(results.into_iter().map(song_from_row).collect())
+}
+
+pub(crate) async fn search_songs_by_title(
+    conn: &mut PgConnection,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<models::Song>, sqlx::Error> {
+    // so that `%` and `_` in the query are matched literally rather than as wildcards
+    let escaped = query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let sql = format!(
+        "
+        select {SONG_COLUMNS} from songs
+        where title ilike '%' || $1 || '%'
+        order by id
+        limit $2
+        "
+    );
+    let results: Vec<SongRow> = sqlx::query_as(&sql)
+        .bind(escaped)
+        .bind(limit)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(results.into_iter().map(song_from_row).collect())
+}
+
+pub(crate) async fn get_songs_missing_date(
+    conn: &mut PgConnection,
+) -> Result<Vec<models::Song>, sqlx::Error> {
+    let sql = format!("select {SONG_COLUMNS} from songs where date_first_sung is null order by id");
+    let results: Vec<SongRow> = sqlx::query_as(&sql).fetch_all(conn).await?;
+
+    Ok(results.into_iter().map(song_from_row).collect())
+}
+
+pub(crate) async fn count_songs_without_segments(
+    conn: &mut PgConnection,
+) -> Result<i64, sqlx::Error> {
+    let (count,): (i64,) = sqlx::query_as(
+        "select count(*) from songs where not exists (select 1 from segments where song_id = songs.id)",
+    )
+    .fetch_one(conn)
+    .await?;
+
+    Ok(count)
+}
+
+pub(crate) async fn count_songs(conn: &mut PgConnection) -> Result<i64, sqlx::Error> {
+    let (count,): (i64,) = sqlx::query_as("select count(*) from songs")
+        .fetch_one(conn)
+        .await?;
+
+    Ok(count)
+}
+
+pub(crate) async fn count_segments(conn: &mut PgConnection) -> Result<i64, sqlx::Error> {
+    let (count,): (i64,) = sqlx::query_as("select count(*) from segments")
+        .fetch_one(conn)
+        .await?;
+
+    Ok(count)
+}
+
+pub(crate) async fn estimated_segment_count(
+    conn: &mut PgConnection,
+) -> Result<Option<i64>, sqlx::Error> {
+    let (estimate,): (f32,) =
+        sqlx::query_as("select reltuples from pg_class where oid = 'segments'::regclass")
+            .fetch_one(conn)
+            .await?;
+
+    // postgres uses -1 for tables that haven't been vacuumed or analyzed yet
+    Ok((estimate >= 0.0).then_some(estimate as i64))
+}
+
+fn song_from_row(
+    (
+        id,
+        title,
+        singer_id,
+        date_first_sung,
+        local_path,
+        joined_paths,
+        samplerate,
+        fft_size,
+        fft_overlap,
+    ): SongRow,
+) -> models::Song {
+    let params = match (samplerate, fft_size, fft_overlap) {
+        (Some(samplerate), Some(fft_size), Some(fft_overlap)) => Some(models::SpectrogramParams {
+            samplerate,
+            fft_size,
+            fft_overlap,
+        }),
+        _ => None,
+    };
+
+    models::Song {
+        id,
+        metadata: models::SongMetadata {
+            title,
+            singer_id,
+            date_first_sung,
+            local_path,
+            joined_paths,
+        },
+        params,
+    }
+}
+
+pub(crate) async fn get_song_spectrogram(
+    conn: &mut PgConnection,
+    song_id: i64,
+) -> Result<Vec<Vec<f32>>, sqlx::Error> {
+    let results: Vec<(Vector,)> =
+        sqlx::query_as("select vec from segments where song_id = $1 order by segment_index")
+            .bind(song_id)
+            .fetch_all(conn)
+            .await?;
+
+    Ok(results.into_iter().map(|(vec,)| vec.to_vec()).collect())
+}
+
+pub(crate) async fn get_song_segments(
+    conn: &mut PgConnection,
+    song_id: i64,
+) -> Result<Vec<models::Sample>, sqlx::Error> {
+    let results: Vec<(i64, Vector, i64, i64)> = sqlx::query_as(
+        "
+        select segment_index, vec, start_ts_ms, end_ts_ms from segments
+        where song_id = $1
+        order by segment_index
+        ",
+    )
+    .bind(song_id)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results
+        .into_iter()
+        .map(
+            |(sample_index, vec, start_ts_ms, end_ts_ms)| models::Sample {
+                song_id,
+                sample_index,
+                sample: vec.to_vec(),
+                start_ts_ms,
+                end_ts_ms,
+            },
+        )
+        .collect())
+}
+
+pub(crate) async fn get_singers(
+    conn: &mut PgConnection,
+) -> Result<HashMap<i16, models::Singer>, sqlx::Error> {
+    let results: Vec<(i16, String)> = sqlx::query_as("select id, s_name from singers")
+        .fetch_all(conn)
+        .await?;
+    let singers = results
+        .into_iter()
+        .map(|(id, name)| models::Singer { id, name })
+        .collect::<Vec<_>>();
+
+    Ok(singers
+        .into_iter()
+        .map(|singer| (singer.id, singer))
+        .collect())
+}
+
+pub(crate) async fn song_count_by_singer(
+    conn: &mut PgConnection,
+) -> Result<HashMap<i16, i64>, sqlx::Error> {
+    let results: Vec<(i16, i64)> = sqlx::query_as(
+        "select singer_id, count(*) from songs where singer_id is not null group by singer_id",
+    )
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results.into_iter().collect())
+}
+
+pub(crate) async fn get_singer(
+    conn: &mut PgConnection,
+    singer_id: i16,
+) -> Result<Option<models::Singer>, sqlx::Error> {
+    let result: Option<(i16, String)> =
+        sqlx::query_as("select id, s_name from singers where id = $1")
+            .bind(singer_id)
+            .fetch_optional(conn)
+            .await?;
+
+    Ok(result.map(|(id, name)| models::Singer { id, name }))
+}
+
+pub(crate) async fn create_singer(conn: &mut PgConnection, name: &str) -> Result<i16, sqlx::Error> {
+    let (id,): (i16,) = sqlx::query_as("insert into singers(s_name) values ($1) returning id")
+        .bind(name)
+        .fetch_one(conn)
+        .await?;
+
+    Ok(id)
+}
+
+pub(crate) async fn update_song_metadata(
+    conn: &mut PgConnection,
+    song_id: i64,
+    metadata: &models::SongMetadata,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query(
+        "
+        update songs
+        set title = $2, singer_id = $3, date_first_sung = $4, local_path = $5, joined_paths = $6
+        where id = $1
+        ",
+    )
+    .bind(song_id)
+    .bind(&metadata.title)
+    .bind(metadata.singer_id)
+    .bind(metadata.date_first_sung)
+    .bind(&metadata.local_path)
+    .bind(&metadata.joined_paths)
+    .execute(conn)
+    .await?
+    .rows_affected();
+
+    Ok(updated > 0)
+}
+
+pub(crate) async fn rename_singer(
+    conn: &mut PgConnection,
+    singer_id: i16,
+    name: &str,
+) -> Result<bool, sqlx::Error> {
+    let updated = sqlx::query("update singers set s_name = $2 where id = $1")
+        .bind(singer_id)
+        .bind(name)
+        .execute(conn)
+        .await?
+        .rows_affected();
+
+    Ok(updated > 0)
+}
+
+pub(crate) async fn get_song_by_path(
+    conn: &mut PgConnection,
+    full_file_path: &str,
+) -> Result<Option<models::Song>, sqlx::Error> {
+    // paths aren't unique, so if a file was somehow uploaded twice this is the first copy
+    let sql = format!(
+        "select {SONG_COLUMNS} from songs where local_path = $1 or $1 = any(joined_paths) \
+         order by id limit 1"
+    );
+    let results: Option<SongRow> = sqlx::query_as(&sql)
+        .bind(full_file_path)
+        .fetch_optional(conn)
+        .await?;
+
+    Ok(results.map(song_from_row))
+}
+
+pub(crate) async fn song_already_saved(
+    conn: &mut PgConnection,
+    full_file_path: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query_as("select 1 from songs where local_path = $1 or $1 = any(joined_paths)")
+        .bind(full_file_path)
+        .fetch_optional(conn)
+        .await
+        .map(|n: Option<(i32,)>| n.is_some())
+}
+
+pub(crate) async fn get_song_duration_ms(
+    conn: &mut PgConnection,
+    song_id: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_as("select max(end_ts_ms) from segments where song_id = $1")
+        .bind(song_id)
+        .fetch_optional(conn)
+        .await
+        .map(|ok| ok.map(|(v,): (i64,)| v))
+}
+
+pub(crate) async fn get_song_segment_count(
+    conn: &mut PgConnection,
+    song_id: i64,
+) -> Result<i64, sqlx::Error> {
+    let (count,): (i64,) = sqlx::query_as("select count(*) from segments where song_id = $1")
+        .bind(song_id)
+        .fetch_one(conn)
+        .await?;
+
+    Ok(count)
+}
+
+pub(crate) async fn get_segment_stats_bulk(
+    conn: &mut PgConnection,
+    song_ids: &[i64],
+) -> Result<HashMap<i64, models::SegmentStats>, sqlx::Error> {
+    let results: Vec<(i64, i64, i64)> = sqlx::query_as(
+        "select song_id, max(end_ts_ms), count(*) from segments where song_id = any($1) \
+         group by song_id",
+    )
+    .bind(song_ids)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(results
+        .into_iter()
+        .map(|(song_id, duration_ms, segment_count)| {
+            let stats = models::SegmentStats {
+                duration_ms,
+                segment_count,
+            };
+            (song_id, stats)
+        })
+        .collect())
+}
+
+pub(crate) async fn touch_song(conn: &mut PgConnection, song_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query("update songs set last_matched_at = now() where id = $1")
+        .bind(song_id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+pub(crate) async fn touch_songs(
+    conn: &mut PgConnection,
+    song_ids: &[i64],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("update songs set last_matched_at = now() where id = any($1)")
+        .bind(song_ids)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+#[instrument(skip(conn), ret, level = "trace")]
+pub(crate) async fn prune_songs(
+    conn: &mut PgConnection,
+    older_than: std::time::Duration,
+) -> Result<u64, sqlx::Error> {
+    // `now()` is fixed for the whole transaction, so both deletes agree on which songs are stale
+    let mut tx = conn.begin().await?;
+
+    sqlx::query(
+        "
+        delete from segments where song_id in (
+            select id from songs where last_matched_at < now() - $1
+        )
+        ",
+    )
+    .bind(older_than)
+    .execute(&mut *tx)
+    .await?;
+
+    let deleted = sqlx::query("delete from songs where last_matched_at < now() - $1")
+        .bind(older_than)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+
+    Ok(deleted)
+}
+
+pub(crate) async fn delete_songs_by_singer(
+    conn: &mut PgConnection,
+    singer_id: i16,
+) -> Result<u64, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+
+    sqlx::query(
+        "delete from segments where song_id in (select id from songs where singer_id = $1)",
+    )
+    .bind(singer_id)
+    .execute(&mut *tx)
+    .await?;
+
+    let deleted = sqlx::query("delete from songs where singer_id = $1")
+        .bind(singer_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The length of the `vec` column in `schema.sql`
+    const VECTOR_LEN: usize = 640;
+
+    /// A connection to the database in `PLINK_TEST_DATABASE_URL`, which `migrate` has to have
+    /// been run on, or `None` to skip the test when it isn't set
+    ///
+    /// Tests do everything in a transaction that's rolled back, so nothing is left behind
+    async fn test_conn() -> Option<PgConnection> {
+        let Ok(url) = std::env::var("PLINK_TEST_DATABASE_URL") else {
+            eprintln!("PLINK_TEST_DATABASE_URL isn't set, skipping");
+            return None;
+        };
+
+        Some(
+            PgConnection::connect(&url)
+                .await
+                .expect("failed to connect to test database"),
+        )
+    }
+
+    fn test_metadata() -> models::SongMetadata {
+        models::SongMetadata {
+            title: "queries test song".to_string(),
+            singer_id: 0,
+            date_first_sung: None,
+            local_path: None,
+            joined_paths: Vec::new(),
+        }
+    }
+
+    /// `n` evenly spaced segments, with `bad_value` in the segment at `bad_index`
+    ///
+    /// There are enough segments for several chunks to have already been sent by the time an
+    /// insert gets to a late `bad_index`
+    fn segments_with(n: usize, bad_index: usize, bad_value: f32) -> Vec<models::NewSegment> {
+        (0..n)
+            .map(|index| {
+                let mut vector = vec![0.5; VECTOR_LEN];
+                if index == bad_index {
+                    vector[VECTOR_LEN / 2] = bad_value;
+                }
+                models::NewSegment {
+                    segment_index: index as i64,
+                    start_offset: index * 960,
+                    vector,
+                }
+            })
+            .collect()
+    }
+
+    async fn count(conn: &mut PgConnection, sql: &str) -> i64 {
+        let (count,): (i64,) = sqlx::query_as(sql).fetch_one(conn).await.unwrap();
+        count
+    }
+
+    #[tokio::test]
+    async fn non_finite_segments_are_rejected_without_writing_anything() {
+        let Some(mut conn) = test_conn().await else {
+            return;
+        };
+        let mut tx = conn.begin().await.unwrap();
+        let segments_before = count(&mut tx, "select count(*) from segments").await;
+
+        for bad_value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let result = insert_new_song(
+                &mut tx,
+                segments_with(200, 150, bad_value).into_iter(),
+                &test_metadata(),
+                30_000,
+                1280,
+                320,
+            )
+            .await;
+
+            assert!(
+                matches!(
+                    result,
+                    Err(InsertError::NonFiniteSegment { segment_index: 150 })
+                ),
+                "inserting {bad_value} gave {result:?}"
+            );
+            assert_eq!(
+                count(&mut tx, "select count(*) from segments").await,
+                segments_before
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_copy_leaves_no_song_behind() {
+        let Some(mut conn) = test_conn().await else {
+            return;
+        };
+        let mut tx = conn.begin().await.unwrap();
+        let songs_sql = "select count(*) from songs where title = 'queries test song'";
+        assert_eq!(count(&mut tx, songs_sql).await, 0);
+
+        // the bad segment is well past the first chunk, so the copy is aborted partway through
+        let result = insert_new_song(
+            &mut tx,
+            segments_with(200, 150, f32::NAN).into_iter(),
+            &test_metadata(),
+            30_000,
+            1280,
+            320,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(count(&mut tx, songs_sql).await, 0);
+        // the connection can still be used after the failed copy
+        insert_new_song(
+            &mut tx,
+            segments_with(3, usize::MAX, 0.0).into_iter(),
+            &test_metadata(),
+            30_000,
+            1280,
+            320,
+        )
+        .await
+        .unwrap();
+        assert_eq!(count(&mut tx, songs_sql).await, 1);
+    }
+
+    #[tokio::test]
+    async fn segment_stats_and_touches_cover_every_song_at_once() {
+        let Some(mut conn) = test_conn().await else {
+            return;
+        };
+        let mut tx = conn.begin().await.unwrap();
+
+        let mut inserted = Vec::new();
+        for n in [3, 5] {
+            let result = insert_new_song(
+                &mut tx,
+                segments_with(n, usize::MAX, 0.0).into_iter(),
+                &test_metadata(),
+                30_000,
+                1280,
+                320,
+            )
+            .await
+            .unwrap();
+            inserted.push(result);
+        }
+        let mut song_ids = inserted
+            .iter()
+            .map(|result| result.song_id)
+            .collect::<Vec<_>>();
+        song_ids.push(-1);
+
+        let stats = get_segment_stats_bulk(&mut tx, &song_ids).await.unwrap();
+        assert_eq!(stats.len(), 2);
+        for result in &inserted {
+            assert_eq!(
+                stats[&result.song_id],
+                models::SegmentStats {
+                    duration_ms: result.duration_ms,
+                    segment_count: result.segment_count as i64,
+                }
+            );
+        }
+
+        sqlx::query(
+            "update songs set last_matched_at = now() - interval '1 day' where id = any($1)",
+        )
+        .bind(&song_ids)
+        .execute(&mut *tx)
+        .await
+        .unwrap();
+        touch_songs(&mut tx, &song_ids).await.unwrap();
+        let stale_sql = "select count(*) from songs \
+                         where title = 'queries test song' and last_matched_at < now()";
+        assert_eq!(count(&mut tx, stale_sql).await, 0);
+    }
+
+    #[tokio::test]
+    async fn joined_paths_count_as_saved() {
+        let Some(mut conn) = test_conn().await else {
+            return;
+        };
+        let mut tx = conn.begin().await.unwrap();
+        let metadata = models::SongMetadata {
+            local_path: Some("/queries-test/side-a.flac".to_string()),
+            joined_paths: vec!["/queries-test/side-b.flac".to_string()],
+            ..test_metadata()
+        };
+
+        let song_id = insert_new_song(
+            &mut tx,
+            segments_with(3, usize::MAX, 0.0).into_iter(),
+            &metadata,
+            30_000,
+            1280,
+            320,
+        )
+        .await
+        .unwrap()
+        .song_id;
+
+        for path in ["/queries-test/side-a.flac", "/queries-test/side-b.flac"] {
+            assert!(song_already_saved(&mut tx, path).await.unwrap(), "{path}");
+            let song = get_song_by_path(&mut tx, path).await.unwrap().unwrap();
+            assert_eq!(song.id, song_id);
+            assert_eq!(song.metadata.joined_paths, metadata.joined_paths);
+        }
+        assert!(!song_already_saved(&mut tx, "/queries-test/side-c.flac")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn skipped_frames_keep_their_original_index() {
+        let Some(mut conn) = test_conn().await else {
+            return;
+        };
+        let mut tx = conn.begin().await.unwrap();
+
+        let kept = [0, 1, 4, 5, 9];
+        let segments = segments_with(10, usize::MAX, 0.0)
+            .into_iter()
+            .filter(|segment| kept.contains(&segment.segment_index))
+            .collect::<Vec<_>>();
+        let inserted = insert_new_song(
+            &mut tx,
+            segments.into_iter(),
+            &test_metadata(),
+            30_000,
+            1280,
+            320,
+        )
+        .await
+        .unwrap();
+
+        let stored: Vec<(i64, i64)> = sqlx::query_as(
+            "select segment_index, start_ts_ms from segments where song_id = $1 order by segment_index",
+        )
+        .bind(inserted.song_id)
+        .fetch_all(&mut *tx)
+        .await
+        .unwrap();
+
+        // each frame is 960 samples, or 32ms, after the last
+        assert_eq!(
+            stored,
+            kept.iter()
+                .map(|index| (*index, index * 32))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(inserted.segment_count, kept.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn find_similar_to_can_use_the_index() {
+        let Some(mut conn) = test_conn().await else {
+            return;
+        };
+        let mut tx = conn.begin().await.unwrap();
+        // small test databases are quicker to scan, so the planner has to be pushed to the index
+        // to show that the query doesn't rule it out
+        sqlx::query("set local enable_seqscan = off")
+            .execute(&mut *tx)
+            .await
+            .unwrap();
+
+        let sql = format!("explain {}", find_similar_sql(models::DistanceMetric::L2));
+        let plan: Vec<(String,)> = sqlx::query_as(&sql)
+            .bind(Vector::from(vec![0.0; VECTOR_LEN]))
+            .bind(1.0)
+            .bind(10_i64)
+            .fetch_all(&mut *tx)
+            .await
+            .unwrap();
+        let plan = plan.into_iter().map(|(line,)| line).collect::<Vec<_>>();
+
+        assert!(
+            plan.iter()
+                .any(|line| line.contains("Index Scan using segments_vec_idx")),
+            "plan doesn't use the index:\n{}",
+            plan.join("\n")
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::HashMap, future::Future};
+
+use crate::{models, Database};
+
+/// The queries needed to match a spectrogram against a set of stored songs
+///
+/// This is implemented by [`Database`] for postgres, and by [`crate::memory::InMemoryDatabase`]
+/// for small sets of songs and for testing without a database
+pub trait SimilaritySearch: Clone + Send + Sync + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Find up to `limit` segments within `thresh` of `vector`, closest first
+    fn find_similar(
+        &self,
+        vector: Vec<f32>,
+        thresh: f64,
+        limit: i64,
+    ) -> impl Future<Output = Result<Vec<models::SimilarMatch>, Self::Error>> + Send;
+
+    fn get_song(
+        &self,
+        song_id: i64,
+    ) -> impl Future<Output = Result<Option<models::Song>, Self::Error>> + Send;
+
+    /// Every song out of `song_ids` that exists, keyed by id
+    fn get_songs_bulk(
+        &self,
+        song_ids: &[i64],
+    ) -> impl Future<Output = Result<HashMap<i64, models::Song>, Self::Error>> + Send;
+
+    fn get_singers(
+        &self,
+    ) -> impl Future<Output = Result<HashMap<i16, models::Singer>, Self::Error>> + Send;
+
+    /// The duration and segment count of every song out of `song_ids` that has any segments,
+    /// keyed by id
+    fn get_segment_stats_bulk(
+        &self,
+        song_ids: &[i64],
+    ) -> impl Future<Output = Result<HashMap<i64, models::SegmentStats>, Self::Error>> + Send;
+
+    /// Record that every song out of `song_ids` was just matched
+    fn touch_songs(&self, song_ids: &[i64])
+        -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+impl SimilaritySearch for Database {
+    type Error = sqlx::Error;
+
+    async fn find_similar(
+        &self,
+        vector: Vec<f32>,
+        thresh: f64,
+        limit: i64,
+    ) -> Result<Vec<models::SimilarMatch>, Self::Error> {
+        self.find_similar_to(vector, thresh, limit).await
+    }
+
+    async fn get_song(&self, song_id: i64) -> Result<Option<models::Song>, Self::Error> {
+        Database::get_song(self, song_id).await
+    }
+
+    async fn get_songs_bulk(
+        &self,
+        song_ids: &[i64],
+    ) -> Result<HashMap<i64, models::Song>, Self::Error> {
+        Database::get_songs_bulk(self, song_ids).await
+    }
+
+    async fn get_singers(&self) -> Result<HashMap<i16, models::Singer>, Self::Error> {
+        Database::get_singers(self).await
+    }
+
+    async fn get_segment_stats_bulk(
+        &self,
+        song_ids: &[i64],
+    ) -> Result<HashMap<i64, models::SegmentStats>, Self::Error> {
+        Database::get_segment_stats_bulk(self, song_ids).await
+    }
+
+    async fn touch_songs(&self, song_ids: &[i64]) -> Result<(), Self::Error> {
+        Database::touch_songs(self, song_ids).await
+    }
+}
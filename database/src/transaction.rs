@@ -0,0 +1,28 @@
+use std::collections::HashMap;
+
+use pgvector::Vector;
+
+use crate::{models, queries, InsertError};
+
+/// A handle to an open transaction, with the same methods as [`crate::Database`]
+///
+/// Dropping this without calling [`DatabaseTx::commit`] rolls the transaction back
+pub struct DatabaseTx<'a> {
+    tx: sqlx::Transaction<'a, sqlx::Postgres>,
+}
+
+impl<'a> DatabaseTx<'a> {
+    pub(crate) fn new(tx: sqlx::Transaction<'a, sqlx::Postgres>) -> Self {
+        Self { tx }
+    }
+
+    pub async fn commit(self) -> Result<(), sqlx::Error> {
+        self.tx.commit().await
+    }
+
+    pub async fn rollback(self) -> Result<(), sqlx::Error> {
+        self.tx.rollback().await
+    }
+
+    query_methods!((&mut self), &mut *self.tx);
+}
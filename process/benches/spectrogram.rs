@@ -0,0 +1,102 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use process::{SpectrogramConfig, SpectrogramGenerator};
+
+const SAMPLERATE: f32 = 30_000.0;
+const FFT_LENS: [usize; 3] = [512, 1280, 4096];
+/// Input lengths in seconds
+const DURATIONS: [f32; 2] = [1.0, 10.0];
+/// Input lengths in seconds for comparing parallel and sequential runs, up to a whole song, as
+/// thread start up hides any difference on short inputs
+#[cfg(feature = "rayon")]
+const PARALLEL_DURATIONS: [f32; 2] = [10.0, 240.0];
+
+/// A sine sweeping exponentially from 50hz up to 10khz over the whole input, so every run sees
+/// the same samples and every bin gets some energy
+fn sine_sweep(seconds: f32) -> Vec<f32> {
+    let len = (seconds * SAMPLERATE) as usize;
+    let (start_hz, end_hz) = (50.0f32, 10_000.0f32);
+    let rate = (end_hz / start_hz).ln() / seconds;
+
+    (0..len)
+        .map(|n| {
+            let t = n as f32 / SAMPLERATE;
+            // the integral of `start_hz * e^(rate * t)`, so the phase stays continuous
+            let phase = std::f32::consts::TAU * start_hz * ((rate * t).exp() - 1.0) / rate;
+            phase.sin()
+        })
+        .collect()
+}
+
+fn config(fft_len: usize) -> SpectrogramConfig {
+    SpectrogramConfig::new(fft_len, fft_len / 4).unwrap()
+}
+
+fn run(c: &mut Criterion) {
+    let mut group = c.benchmark_group("run");
+
+    for seconds in DURATIONS {
+        let samples = sine_sweep(seconds);
+        group.throughput(Throughput::Elements(samples.len() as u64));
+
+        for fft_len in FFT_LENS {
+            let config = config(fft_len);
+            let id = format!("{fft_len}/{seconds}s");
+
+            let warm: SpectrogramGenerator<f32> = SpectrogramGenerator::default();
+            warm.warm(&config);
+            group.bench_with_input(BenchmarkId::new("warm", &id), &samples, |b, samples| {
+                b.iter(|| warm.run(samples, &config))
+            });
+
+            group.bench_with_input(BenchmarkId::new("cold", &id), &samples, |b, samples| {
+                b.iter(|| SpectrogramGenerator::<f32>::default().run(samples, &config))
+            });
+        }
+    }
+
+    group.finish();
+}
+
+/// [`SpectrogramGenerator::run`] computes frames in parallel with the `rayon` feature, while
+/// [`SpectrogramGenerator::run_streaming`] always computes them one after another
+#[cfg(feature = "rayon")]
+fn parallel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rayon");
+    // a whole song takes long enough per iteration that the default 100 samples would take ages
+    group.sample_size(10);
+
+    for seconds in PARALLEL_DURATIONS {
+        let samples = sine_sweep(seconds);
+        group.throughput(Throughput::Elements(samples.len() as u64));
+
+        for fft_len in FFT_LENS {
+            let config = config(fft_len);
+            let id = format!("{fft_len}/{seconds}s");
+            let generator: SpectrogramGenerator<f32> = SpectrogramGenerator::default();
+            generator.warm(&config);
+
+            group.bench_with_input(BenchmarkId::new("parallel", &id), &samples, |b, samples| {
+                b.iter(|| generator.run(samples, &config))
+            });
+            group.bench_with_input(
+                BenchmarkId::new("sequential", &id),
+                &samples,
+                |b, samples| {
+                    b.iter(|| {
+                        generator
+                            .run_streaming(samples, &config)
+                            .collect::<Vec<_>>()
+                    })
+                },
+            );
+        }
+    }
+
+    group.finish();
+}
+
+#[cfg(feature = "rayon")]
+criterion_group!(benches, run, parallel);
+#[cfg(not(feature = "rayon"))]
+criterion_group!(benches, run);
+criterion_main!(benches);
@@ -0,0 +1,212 @@
+use crate::ConfigError;
+
+/// Q for a butterworth (maximally flat) second order section
+const BUTTERWORTH_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// A single second order iir filter section, using the coefficients from the RBJ audio eq cookbook
+#[derive(Debug, Clone, Copy)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    /// `cutoff_hz` has to be above 0 and below nyquist, `samplerate / 2`, otherwise the filter is
+    /// unstable and its output grows without bound, see [`BandPassConfig::validate`]
+    pub fn low_pass(cutoff_hz: f32, samplerate: usize, q: f32) -> Self {
+        let (cos, alpha) = Self::intermediates(cutoff_hz, samplerate, q);
+
+        Self::normalized(
+            (1.0 - cos) / 2.0,
+            1.0 - cos,
+            (1.0 - cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    /// Like [`Self::low_pass`], `cutoff_hz` has to be above 0 and below nyquist
+    pub fn high_pass(cutoff_hz: f32, samplerate: usize, q: f32) -> Self {
+        let (cos, alpha) = Self::intermediates(cutoff_hz, samplerate, q);
+
+        Self::normalized(
+            (1.0 + cos) / 2.0,
+            -(1.0 + cos),
+            (1.0 + cos) / 2.0,
+            1.0 + alpha,
+            -2.0 * cos,
+            1.0 - alpha,
+        )
+    }
+
+    /// Run the filter over `samples` in place
+    pub fn process(&self, samples: &mut [f32]) {
+        // transposed direct form ii
+        let (mut z1, mut z2) = (0.0, 0.0);
+
+        for sample in samples.iter_mut() {
+            let input = *sample;
+            let output = self.b0 * input + z1;
+            z1 = self.b1 * input - self.a1 * output + z2;
+            z2 = self.b2 * input - self.a2 * output;
+            *sample = output;
+        }
+    }
+
+    fn intermediates(cutoff_hz: f32, samplerate: usize, q: f32) -> (f32, f32) {
+        let w0 = std::f32::consts::TAU * cutoff_hz / samplerate as f32;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    fn normalized(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// A band-pass made of an optional high-pass at `low_cut_hz` followed by an optional low-pass at `high_cut_hz`
+///
+/// Both cutoffs have to be below the nyquist frequency of the samples being filtered, which is
+/// checked by [`Self::apply`]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BandPassConfig {
+    pub low_cut_hz: Option<f32>,
+    pub high_cut_hz: Option<f32>,
+}
+
+impl BandPassConfig {
+    /// Check that each cutoff is above 0 and below nyquist, `samplerate / 2`, and that the low cut
+    /// is below the high cut
+    pub fn validate(&self, samplerate: usize) -> Result<(), ConfigError> {
+        let nyquist = samplerate as f32 / 2.0;
+        for cutoff_hz in [self.low_cut_hz, self.high_cut_hz].into_iter().flatten() {
+            // written so that a NaN is rejected too
+            if !(cutoff_hz > 0.0 && cutoff_hz < nyquist) {
+                return Err(ConfigError::CutoffOutOfRange { samplerate });
+            }
+        }
+        if let (Some(low_cut_hz), Some(high_cut_hz)) = (self.low_cut_hz, self.high_cut_hz) {
+            if low_cut_hz >= high_cut_hz {
+                return Err(ConfigError::CutoffsCrossed);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Filter `samples` in place, doing nothing if neither cutoff is set
+    ///
+    /// The cutoffs are checked against `samplerate` first, as a cutoff at or above nyquist would
+    /// fill `samples` with infinities, and the samples are left untouched if they're invalid
+    pub fn apply(&self, samples: &mut [f32], samplerate: usize) -> Result<(), ConfigError> {
+        self.validate(samplerate)?;
+
+        if let Some(low_cut_hz) = self.low_cut_hz {
+            Biquad::high_pass(low_cut_hz, samplerate, BUTTERWORTH_Q).process(samples);
+        }
+
+        if let Some(high_cut_hz) = self.high_cut_hz {
+            Biquad::low_pass(high_cut_hz, samplerate, BUTTERWORTH_Q).process(samples);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLERATE: usize = 30_000;
+
+    fn sine(hz: f32) -> Vec<f32> {
+        (0..SAMPLERATE)
+            .map(|i| (std::f32::consts::TAU * hz * i as f32 / SAMPLERATE as f32).sin())
+            .collect()
+    }
+
+    /// The rms of `samples` after the filter has settled, relative to a unit sine's
+    fn gain(samples: &[f32]) -> f32 {
+        let settled = &samples[samples.len() / 2..];
+        let rms = (settled.iter().map(|sample| sample * sample).sum::<f32>()
+            / settled.len() as f32)
+            .sqrt();
+        rms * std::f32::consts::SQRT_2
+    }
+
+    fn vocal_band() -> BandPassConfig {
+        BandPassConfig {
+            low_cut_hz: Some(80.0),
+            high_cut_hz: Some(8_000.0),
+        }
+    }
+
+    #[test]
+    fn passes_the_band_unchanged() {
+        let mut samples = sine(1_000.0);
+        vocal_band().apply(&mut samples, SAMPLERATE).unwrap();
+
+        let gain = gain(&samples);
+        assert!((gain - 1.0).abs() < 0.02, "gain was {gain}");
+    }
+
+    #[test]
+    fn attenuates_outside_the_band() {
+        // a second order section falls 12db per octave, so two octaves out is at least 20db down
+        for hz in [20.0, 13_000.0] {
+            let mut samples = sine(hz);
+            vocal_band().apply(&mut samples, SAMPLERATE).unwrap();
+
+            let gain = gain(&samples);
+            assert!(gain < 0.1, "gain at {hz}hz was {gain}");
+        }
+    }
+
+    #[test]
+    fn rejects_cutoffs_at_or_above_nyquist() {
+        let config = BandPassConfig {
+            low_cut_hz: None,
+            high_cut_hz: Some(16_000.0),
+        };
+        let mut samples = sine(1_000.0);
+
+        assert_eq!(
+            config.apply(&mut samples, SAMPLERATE),
+            Err(ConfigError::CutoffOutOfRange {
+                samplerate: SAMPLERATE
+            })
+        );
+        assert_eq!(samples, sine(1_000.0));
+    }
+
+    #[test]
+    fn rejects_empty_and_crossed_bands() {
+        let rejected = |low_cut_hz, high_cut_hz| {
+            BandPassConfig {
+                low_cut_hz,
+                high_cut_hz,
+            }
+            .validate(SAMPLERATE)
+        };
+
+        assert!(rejected(Some(0.0), None).is_err());
+        assert!(rejected(Some(-80.0), None).is_err());
+        assert_eq!(
+            rejected(Some(8_000.0), Some(80.0)),
+            Err(ConfigError::CutoffsCrossed)
+        );
+        assert_eq!(
+            rejected(Some(80.0), Some(80.0)),
+            Err(ConfigError::CutoffsCrossed)
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use std::ops::Range;
+
+use crate::{mel, Float};
+
+/// How the linear fft bins of each frame are grouped into the output vector
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FreqGrouping {
+    /// Output every bin as is
+    Linear,
+    /// Average the bins into `n_bands` logarithmically spaced bands, giving more resolution to
+    /// lower frequencies
+    ///
+    /// If there are fewer bins than bands, there is one band per bin instead
+    Log { n_bands: usize },
+    /// Replace the bins with the outputs of a mel filterbank, see [`mel::MelConfig`]
+    Mel(mel::MelConfig),
+}
+
+/// The range of bins in each of `n_bands` logarithmically spaced bands covering `n_bins` bins
+///
+/// Each band has at least one bin, so the lowest bands end up linearly spaced
+pub(crate) fn log_bands(n_bins: usize, n_bands: usize) -> Vec<Range<usize>> {
+    let n_bands = n_bands.min(n_bins);
+    if n_bands == 0 {
+        return Vec::new();
+    }
+
+    let mut bands = Vec::with_capacity(n_bands);
+    let mut start = 0;
+
+    for band in 0..n_bands {
+        // bands left after this one, which each need at least a bin
+        let remaining = n_bands - band - 1;
+        let ideal = (n_bins as f64)
+            .powf((band + 1) as f64 / n_bands as f64)
+            .round() as usize;
+        let end = ideal.max(start + 1).min(n_bins - remaining);
+        bands.push(start..end);
+        start = end;
+    }
+
+    bands
+}
+
+pub(crate) fn average_bands<T: Float>(frame: &[T], bands: &[Range<usize>]) -> Vec<T> {
+    bands
+        .iter()
+        .map(|band| {
+            let sum = frame[band.clone()]
+                .iter()
+                .fold(T::zero(), |sum, value| sum + *value);
+            sum / T::from_usize(band.len()).unwrap()
+        })
+        .collect()
+}
@@ -0,0 +1,47 @@
+//! The spectral peaks of a spectrogram, as a sparse alternative to storing every frame
+//!
+//! This is a thin layer over [`crate::SpectrogramGenerator::peaks_2d`] for when only where the
+//! peaks are is needed, not how loud they were
+
+use crate::peaks;
+
+/// Options for [`extract_peaks`]
+pub type PeakConfig = peaks::Peak2dConfig;
+
+/// The `(time_index, freq_bin)` of every local maximum of `spectrogram` that's above its
+/// neighbourhood's threshold, ordered by time and then frequency
+pub fn extract_peaks(spectrogram: &[Vec<f32>], config: &PeakConfig) -> Vec<(usize, usize)> {
+    peaks::peaks_2d(spectrogram, config)
+        .into_iter()
+        .map(|peak| (peak.frame, peak.bin))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_a_single_spike() {
+        let mut spectrogram = vec![vec![0.0; 32]; 20];
+        spectrogram[12][5] = 1.0;
+
+        assert_eq!(
+            extract_peaks(&spectrogram, &PeakConfig::default()),
+            vec![(12, 5)]
+        );
+    }
+
+    #[test]
+    fn peaks_are_time_then_frequency() {
+        let mut spectrogram = vec![vec![0.0; 16]; 40];
+        spectrogram[5][12] = 1.0;
+        spectrogram[30][2] = 1.0;
+
+        let peaks = extract_peaks(&spectrogram, &PeakConfig::default());
+        assert_eq!(peaks, vec![(5, 12), (30, 2)]);
+        for (time_index, freq_bin) in peaks {
+            assert_eq!(spectrogram[time_index][freq_bin], 1.0);
+        }
+    }
+}
@@ -1,26 +1,53 @@
 use std::{
     collections::HashMap,
+    ops::Range,
     sync::{Arc, Mutex, RwLock},
 };
 
-use rustfft::{FftNum, FftPlanner};
+use rustfft::{Fft, FftNum, FftPlanner};
 use tracing::instrument;
 
+/// The bins of [`SpectrogramGenerator::run_complex`]
+pub use num_complex::Complex;
+
+pub mod filter;
+pub mod grouping;
+pub mod landmarks;
+pub mod mel;
+pub mod onset;
+pub mod peaks;
+pub mod window;
+
 pub trait Float: FftNum + num_traits::Float {}
 impl Float for f32 {}
 impl Float for f64 {}
 
+/// A map of lazily generated values shared between clones of a [`SpectrogramGenerator`]
+type Cache<K, V> = Arc<RwLock<HashMap<K, Arc<V>>>>;
+
 #[derive(Clone)]
 pub struct SpectrogramGenerator<T: Float> {
     planner: Arc<Mutex<FftPlanner<T>>>,
-    haans: Arc<RwLock<HashMap<usize, Arc<Vec<f32>>>>>,
+    /// Planned ffts keyed by length, so the planner's lock is only taken the first time each
+    /// length is used
+    forward_ffts: Cache<usize, dyn Fft<T>>,
+    inverse_ffts: Cache<usize, dyn Fft<T>>,
+    /// Windows keyed by `(size, function, symmetric)`
+    windows: Cache<(usize, window::WindowFunction, bool), Vec<f32>>,
+    /// Log band ranges keyed by `(n_bins, n_bands)`
+    log_bands: Cache<(usize, usize), Vec<Range<usize>>>,
+    mel_filterbanks: Cache<mel::MelKey, Vec<mel::MelFilter>>,
 }
 
 impl<T: Float> Default for SpectrogramGenerator<T> {
     fn default() -> Self {
         Self {
             planner: Arc::new(Mutex::new(FftPlanner::new())),
-            haans: Default::default(),
+            forward_ffts: Default::default(),
+            inverse_ffts: Default::default(),
+            windows: Default::default(),
+            log_bands: Default::default(),
+            mel_filterbanks: Default::default(),
         }
     }
 }
@@ -33,70 +60,410 @@ impl<T: Float> SpectrogramGenerator<T> {
         }
     }
 
+    /// Plan the fft and generate the window, bands and filterbank that `config` needs ahead of
+    /// time, so the first spectrogram generated with it isn't slower than the rest
+    ///
+    /// Panics if the config isn't valid, see [`SpectrogramConfig::validate`]
+    pub fn warm(&self, config: &SpectrogramConfig) {
+        self.plan(config);
+    }
+
+    /// Generate the spectrogram of `samples`, which can be any float type, so that `f64` audio
+    /// doesn't have to lose precision being converted to `f32` first
     #[instrument(skip(self, samples), level = "trace")]
-    pub fn run(&self, samples: &[f32], config: &SpectrogramConfig) -> Vec<Vec<T>> {
-        let mut planner_guard = self.planner.lock().unwrap();
-        let fft = planner_guard.plan_fft_forward(config.fft_len);
-        drop(planner_guard);
-        let hann = self.get_hann(config.fft_len);
-        let hann_slice = hann.as_slice();
-
-        let spectrogram = samples
-            .windows(config.fft_len)
-            .step_by(config.fft_len - config.overlap)
-            .map(|window| {
-                window
-                    .into_iter()
-                    .zip(hann_slice)
-                    .map(|(sample, hann)| sample * hann)
-                    .map(|scaled| {
-                        num_complex::Complex::new(T::from_f32(scaled).unwrap(), T::zero())
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .map(|mut window| {
-                fft.process(window.as_mut_slice());
-                window
-            })
-            .map(|complex| {
-                complex
-                    .into_iter()
-                    // half the the fft is mirrored due to complex inputs
-                    .take(config.fft_len / 2)
-                    .map(|val| val.norm_sqr().sqrt())
+    pub fn run<S: Float>(&self, samples: &[S], config: &SpectrogramConfig) -> Vec<Vec<T>> {
+        self.run_at(samples, config, &config.frame_starts(samples))
+    }
+
+    /// Like [`Self::run`], but generating each frame lazily as the iterator is advanced, so the
+    /// whole spectrogram never has to be held in memory at once
+    ///
+    /// Panics if the config isn't valid, see [`SpectrogramConfig::validate`]
+    pub fn run_streaming<'a, S: Float>(
+        &self,
+        samples: &'a [S],
+        config: &SpectrogramConfig,
+    ) -> impl Iterator<Item = Vec<T>> + 'a {
+        let plan = self.plan(config);
+
+        config
+            .frame_starts(samples)
+            .into_iter()
+            .map(move |start| plan.magnitudes(samples, start))
+    }
+
+    /// Like [`Self::run`], but with a frame starting at each offset in `frame_starts` instead of
+    /// using [`SpectrogramConfig::frame_starts`]
+    ///
+    /// Any frame running past the end of `samples` is zero padded
+    ///
+    /// Panics if the config isn't valid, see [`SpectrogramConfig::validate`]
+    #[instrument(skip(self, samples, frame_starts), level = "trace")]
+    pub fn run_at<S: Float>(
+        &self,
+        samples: &[S],
+        config: &SpectrogramConfig,
+        frame_starts: &[usize],
+    ) -> Vec<Vec<T>> {
+        let plan = self.plan(config);
+
+        // every frame is independent, and collecting keeps them in order either way
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            frame_starts
+                .par_iter()
+                .map(|start| plan.magnitudes(samples, *start))
+                .collect()
+        }
+        #[cfg(not(feature = "rayon"))]
+        {
+            frame_starts
+                .iter()
+                .map(|start| plan.magnitudes(samples, *start))
+                .collect()
+        }
+    }
+
+    /// The complex spectrum of each frame of `samples`, keeping only the first
+    /// [`SpectrogramConfig::n_bins`] bins, for features that need the phase which [`Self::run`] throws away
+    ///
+    /// [`Self::run`] is the magnitudes of these frames, before any scaling, grouping or
+    /// normalization from `config` is applied
+    ///
+    /// Panics if the config isn't valid, see [`SpectrogramConfig::validate`]
+    #[instrument(skip(self, samples), level = "trace")]
+    pub fn run_complex<S: Float>(
+        &self,
+        samples: &[S],
+        config: &SpectrogramConfig,
+    ) -> Vec<Vec<Complex<T>>> {
+        self.stft(samples, config, &config.frame_starts(samples))
+    }
+
+    /// The windowed fft of each frame, keeping only the first [`SpectrogramConfig::n_bins`] bins
+    fn stft<S: Float>(
+        &self,
+        samples: &[S],
+        config: &SpectrogramConfig,
+        frame_starts: &[usize],
+    ) -> Vec<Vec<Complex<T>>> {
+        let plan = self.plan(config);
+
+        frame_starts
+            .iter()
+            .map(|start| plan.spectrum(samples, *start))
+            .collect()
+    }
+
+    /// Gather everything that's shared between frames generated with `config`
+    fn plan(&self, config: &SpectrogramConfig) -> FramePlan<T> {
+        config.validate().expect("invalid spectrogram config");
+
+        let fft = self.get_fft(config.fft_len, false);
+        let window = self.get_window(config.fft_len, config.window, config.symmetric_window);
+
+        let scale = match config.compensate_overlap {
+            // the coherent gain of the window, which doesn't depend on the hop, so frames at
+            // any overlap measure a tone the same way
+            true => 1.0 / window.iter().sum::<f32>(),
+            false => 1.0,
+        };
+        let grouping = match config.freq_grouping {
+            grouping::FreqGrouping::Linear => PlannedGrouping::Linear,
+            grouping::FreqGrouping::Log { n_bands } => {
+                PlannedGrouping::Bands(self.get_log_bands(config.n_bins(), n_bands))
+            }
+            grouping::FreqGrouping::Mel(mel_config) => PlannedGrouping::Mel(
+                self.get_mel_filterbank(config.fft_len, config.n_bins(), &mel_config),
+            ),
+        };
+
+        FramePlan {
+            fft,
+            window: window
+                .iter()
+                .map(|weight| T::from_f32(*weight).unwrap())
+                .collect(),
+            fft_len: config.fft_len,
+            n_bins: config.n_bins(),
+            scale: T::from_f32(scale).unwrap(),
+            grouping,
+            amplitude_scale: config.amplitude_scale,
+            normalization: config.normalization,
+        }
+    }
+
+    /// A cheap reconstruction of audio from a magnitude spectrogram, treating every bin as
+    /// having zero phase
+    ///
+    /// This sounds rough, but is good enough to get an idea of what a spectrogram contains
+    /// without the cost of iterative phase estimation
+    #[instrument(skip(self, spectrogram, frame_starts), level = "trace")]
+    pub fn reconstruct_fast(
+        &self,
+        spectrogram: &[Vec<T>],
+        config: &SpectrogramConfig,
+        frame_starts: &[usize],
+    ) -> Vec<T> {
+        let frames = spectrogram
+            .iter()
+            .map(|frame| {
+                frame
+                    .iter()
+                    .map(|magnitude| Complex::new(*magnitude, T::zero()))
                     .collect::<Vec<_>>()
             })
             .collect::<Vec<_>>();
 
-        spectrogram
+        self.inverse(&frames, config, frame_starts)
+    }
+
+    /// Turn frames from [`Self::stft`] back into samples with a weighted overlap-add
+    ///
+    /// The mirrored half of each frame is rebuilt from the kept half, so any bins that were
+    /// dropped (like the nyquist bin) are treated as zero
+    #[instrument(skip(self, frames, frame_starts), level = "trace")]
+    fn inverse(
+        &self,
+        frames: &[Vec<Complex<T>>],
+        config: &SpectrogramConfig,
+        frame_starts: &[usize],
+    ) -> Vec<T> {
+        let fft_len = config.fft_len;
+        let ifft = self.get_fft(fft_len, true);
+        let window = self.get_window(fft_len, config.window, config.symmetric_window);
+
+        let out_len = match frame_starts.last() {
+            Some(start) => start + fft_len,
+            None => 0,
+        };
+        let mut out = vec![T::zero(); out_len];
+        let mut norm = vec![T::zero(); out_len];
+        let scale = T::one() / T::from_usize(fft_len).unwrap();
+
+        for (frame, offset) in frames.iter().zip(frame_starts) {
+            let kept = frame.len();
+            let mut full = (0..fft_len)
+                .map(|bin| match bin {
+                    bin if bin < kept => frame[bin],
+                    bin if fft_len - bin < kept => frame[fft_len - bin].conj(),
+                    _ => Complex::new(T::zero(), T::zero()),
+                })
+                .collect::<Vec<_>>();
+            ifft.process(&mut full);
+
+            for (n, (value, weight)) in full.iter().zip(window.iter()).enumerate() {
+                let weight = T::from_f32(*weight).unwrap();
+                out[offset + n] = out[offset + n] + value.re * scale * weight;
+                norm[offset + n] = norm[offset + n] + weight * weight;
+            }
+        }
+
+        let epsilon = T::from_f32(1e-8).unwrap();
+        out.into_iter()
+            .zip(norm)
+            .map(|(value, norm)| match norm > epsilon {
+                true => value / norm,
+                false => T::zero(),
+            })
+            .collect()
+    }
+
+    /// Find the points of `spectrogram` that are the maximum of their
+    /// `±time_radius × ±freq_radius` neighbourhood and above an adaptive threshold, in order of
+    /// frame then bin
+    ///
+    /// Every point of a flat plateau is reported as a peak
+    #[instrument(skip(self, spectrogram), level = "trace")]
+    pub fn peaks_2d(
+        &self,
+        spectrogram: &[Vec<T>],
+        config: peaks::Peak2dConfig,
+    ) -> Vec<peaks::Peak<T>> {
+        peaks::peaks_2d(spectrogram, &config)
+    }
+
+    fn get_fft(&self, len: usize, inverse: bool) -> Arc<dyn Fft<T>> {
+        let cache = match inverse {
+            true => &self.inverse_ffts,
+            false => &self.forward_ffts,
+        };
+        let read = cache.read().unwrap();
+
+        match read.get(&len) {
+            Some(fft) => fft.clone(),
+            None => {
+                drop(read);
+                let mut planner_guard = self.planner.lock().unwrap();
+                let fft = match inverse {
+                    true => planner_guard.plan_fft_inverse(len),
+                    false => planner_guard.plan_fft_forward(len),
+                };
+                drop(planner_guard);
+                let mut write = cache.write().unwrap();
+                write.insert(len, fft.clone());
+                fft
+            }
+        }
     }
 
-    fn get_hann(&self, size: usize) -> Arc<Vec<f32>> {
-        let read = self.haans.read().unwrap();
+    fn get_window(
+        &self,
+        size: usize,
+        function: window::WindowFunction,
+        symmetric: bool,
+    ) -> Arc<Vec<f32>> {
+        let read = self.windows.read().unwrap();
 
-        match read.contains_key(&size) {
-            true => read.get(&size).unwrap().to_owned(),
+        match read.contains_key(&(size, function, symmetric)) {
+            true => read.get(&(size, function, symmetric)).unwrap().to_owned(),
             false => {
                 drop(read);
-                self.generate_hann(size)
+                self.generate_window(size, function, symmetric)
+            }
+        }
+    }
+
+    fn get_log_bands(&self, n_bins: usize, n_bands: usize) -> Arc<Vec<Range<usize>>> {
+        let read = self.log_bands.read().unwrap();
+
+        match read.get(&(n_bins, n_bands)) {
+            Some(bands) => bands.clone(),
+            None => {
+                drop(read);
+                let bands = Arc::new(grouping::log_bands(n_bins, n_bands));
+                let mut write = self.log_bands.write().unwrap();
+                write.insert((n_bins, n_bands), bands.clone());
+                bands
+            }
+        }
+    }
+
+    fn get_mel_filterbank(
+        &self,
+        fft_len: usize,
+        n_bins: usize,
+        config: &mel::MelConfig,
+    ) -> Arc<Vec<mel::MelFilter>> {
+        let key = config.key(fft_len, n_bins);
+        let read = self.mel_filterbanks.read().unwrap();
+
+        match read.get(&key) {
+            Some(filterbank) => filterbank.clone(),
+            None => {
+                drop(read);
+                let filterbank = Arc::new(mel::filterbank(fft_len, n_bins, config));
+                let mut write = self.mel_filterbanks.write().unwrap();
+                write.insert(key, filterbank.clone());
+                filterbank
             }
         }
     }
 
     #[instrument(skip(self), level = "trace")]
-    fn generate_hann(&self, size: usize) -> Arc<Vec<f32>> {
-        let hann = generate_hanning_window(size);
-        let hann = Arc::new(hann);
-        let mut write = self.haans.write().unwrap();
-        write.insert(size, hann.clone());
-        hann
+    fn generate_window(
+        &self,
+        size: usize,
+        function: window::WindowFunction,
+        symmetric: bool,
+    ) -> Arc<Vec<f32>> {
+        let window = Arc::new(function.generate(size, symmetric));
+        let mut write = self.windows.write().unwrap();
+        write.insert((size, function, symmetric), window.clone());
+        window
+    }
+}
+
+/// Everything needed to generate a single frame, planned once and shared between all of them
+struct FramePlan<T: Float> {
+    fft: Arc<dyn Fft<T>>,
+    window: Vec<T>,
+    fft_len: usize,
+    n_bins: usize,
+    scale: T,
+    grouping: PlannedGrouping,
+    amplitude_scale: AmplitudeScale,
+    normalization: Normalization,
+}
+
+/// A [`grouping::FreqGrouping`] with whatever it groups the bins with looked up from the caches
+enum PlannedGrouping {
+    Linear,
+    Bands(Arc<Vec<Range<usize>>>),
+    Mel(Arc<Vec<mel::MelFilter>>),
+}
+
+impl<T: Float> FramePlan<T> {
+    /// The windowed fft of the frame starting at `start`, keeping only the first `n_bins` bins
+    fn spectrum<S: Float>(&self, samples: &[S], start: usize) -> Vec<Complex<T>> {
+        // a padded last frame runs past the end, which is filled in with zeros
+        let end = (start + self.fft_len).min(samples.len());
+        let mut complex = samples[start.min(end)..end]
+            .iter()
+            .map(|sample| T::from(*sample).unwrap())
+            .chain(std::iter::repeat(T::zero()))
+            .zip(self.window.iter())
+            .map(|(sample, weight)| Complex::new(sample * *weight, T::zero()))
+            .collect::<Vec<_>>();
+        self.fft.process(complex.as_mut_slice());
+        // half the the fft is mirrored due to complex inputs
+        complex.truncate(self.n_bins);
+        complex
+    }
+
+    /// The magnitudes of the frame starting at `start`, as they appear in the output
+    fn magnitudes<S: Float>(&self, samples: &[S], start: usize) -> Vec<T> {
+        let magnitudes = self
+            .spectrum(samples, start)
+            .into_iter()
+            .map(|val| val.norm_sqr().sqrt() * self.scale)
+            .collect::<Vec<_>>();
+
+        let mut magnitudes = match &self.grouping {
+            PlannedGrouping::Linear => magnitudes,
+            PlannedGrouping::Bands(bands) => grouping::average_bands(&magnitudes, bands),
+            PlannedGrouping::Mel(filterbank) => mel::apply_filterbank(&magnitudes, filterbank),
+        };
+        self.amplitude_scale.apply(&mut magnitudes);
+        self.normalization.apply(&mut magnitudes);
+        magnitudes
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SpectrogramConfig {
     pub fft_len: usize,
     pub overlap: usize,
+    /// How many bins of each fft are kept, counting up from DC, see [`Self::n_bins`]
+    pub keep_bins: Option<usize>,
+    pub framing: Framing,
+    /// Scale each frame's magnitudes by `1 / sum(window)`, so a sine of amplitude `a` measures
+    /// `a / 2` in its bin whatever the `overlap`, `fft_len` or window
+    ///
+    /// This deliberately doesn't depend on the hop, scaling by the window's overlap-add gain
+    /// would tie each frame's magnitudes to the overlap and make spectrograms generated with
+    /// different overlaps less comparable rather than more
+    pub compensate_overlap: bool,
+    /// How bins are grouped in the output, changing the length of each frame
+    pub freq_grouping: grouping::FreqGrouping,
+    /// The window applied to each frame
+    pub window: window::WindowFunction,
+    /// Use the symmetric form of `window` rather than the periodic one, see
+    /// [`window::WindowFunction::generate`]
+    ///
+    /// Fingerprints are generated with the periodic form, so this should stay `false` unless
+    /// comparing against a reference implementation that uses symmetric windows
+    pub symmetric_window: bool,
+    /// Add a zero padded frame covering any samples after the last complete frame, so the end
+    /// of the input isn't dropped
+    ///
+    /// Input shorter than `fft_len` then gives a single frame rather than none
+    pub pad_last: bool,
+    /// Applied to each frame once its magnitudes have been computed and grouped
+    pub amplitude_scale: AmplitudeScale,
+    /// Applied to each frame after [`Self::amplitude_scale`]
+    pub normalization: Normalization,
 }
 
 impl Default for SpectrogramConfig {
@@ -104,16 +471,415 @@ impl Default for SpectrogramConfig {
         Self {
             fft_len: 80,
             overlap: 8,
+            keep_bins: None,
+            framing: Framing::Fixed,
+            compensate_overlap: false,
+            freq_grouping: grouping::FreqGrouping::Linear,
+            window: window::WindowFunction::Hann,
+            symmetric_window: false,
+            pad_last: false,
+            amplitude_scale: AmplitudeScale::Linear,
+            normalization: Normalization::None,
+        }
+    }
+}
+
+impl SpectrogramConfig {
+    /// A config with the given `fft_len` and `overlap`, and everything else left as default
+    pub fn new(fft_len: usize, overlap: usize) -> Result<Self, ConfigError> {
+        let config = Self {
+            fft_len,
+            overlap,
+            ..Default::default()
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like [`Self::new`], but with the overlap given as a fraction of `fft_len`, so the hop stays
+    /// proportional when experimenting with different fft lengths
+    pub fn with_overlap_fraction(fft_len: usize, fraction: f64) -> Result<Self, ConfigError> {
+        if !(0.0..1.0).contains(&fraction) {
+            return Err(ConfigError::OverlapFractionOutOfRange);
+        }
+
+        Self::new(fft_len, (fft_len as f64 * fraction) as usize)
+    }
+
+    /// How many samples apart consecutive frames start, `fft_len - overlap`
+    ///
+    /// This is also used by the database to work out where stored frames started, so the two
+    /// always agree on segment timing
+    pub fn hop(&self) -> usize {
+        self.fft_len - self.overlap
+    }
+
+    /// How many bins each frame of the fft has before any grouping, `keep_bins` if it's set
+    ///
+    /// Bin `k` covers `k * samplerate / fft_len` hz. By default this is `fft_len / 2`, so bins
+    /// run from DC up to but not including nyquist, as the rest of a real input's fft just
+    /// mirrors them. Use `fft_len / 2 + 1` to include the nyquist bin too, which is the most
+    /// that can be kept.
+    pub fn n_bins(&self) -> usize {
+        self.keep_bins.unwrap_or(self.fft_len / 2)
+    }
+
+    /// Check that frames can actually be generated with this config
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.fft_len == 0 {
+            return Err(ConfigError::EmptyFft);
+        }
+        if self.overlap >= self.fft_len {
+            return Err(ConfigError::OverlapTooLarge {
+                fft_len: self.fft_len,
+                overlap: self.overlap,
+            });
+        }
+        if let Some(keep_bins) = self.keep_bins {
+            if keep_bins == 0 || keep_bins > self.fft_len / 2 + 1 {
+                return Err(ConfigError::KeepBinsOutOfRange {
+                    fft_len: self.fft_len,
+                    keep_bins,
+                });
+            }
+        }
+        if let grouping::FreqGrouping::Mel(mel) = &self.freq_grouping {
+            mel.validate()?;
+        }
+        // decibels are mostly negative, and `ln(1 + x)` isn't defined for anything below -1
+        if matches!(self.amplitude_scale, AmplitudeScale::Db { .. })
+            && self.normalization == Normalization::Log
+        {
+            return Err(ConfigError::LogOfDecibels);
+        }
+
+        Ok(())
+    }
+
+    /// The offset of the first sample of each frame that fits entirely within `samples`, followed
+    /// by a partial frame covering the rest of `samples` if [`Self::pad_last`] is set
+    ///
+    /// Panics if the config isn't valid, see [`Self::validate`]
+    pub fn frame_starts<S: Float>(&self, samples: &[S]) -> Vec<usize> {
+        self.validate().expect("invalid spectrogram config");
+        let hop = self.hop();
+        let mut starts = self.complete_frame_starts(samples);
+
+        if self.pad_last {
+            match starts.last() {
+                Some(last) if last + self.fft_len < samples.len() => starts.push(last + hop),
+                Some(_) => {}
+                None => starts.push(0),
+            }
+        }
+
+        starts
+    }
+
+    fn complete_frame_starts<S: Float>(&self, samples: &[S]) -> Vec<usize> {
+        let hop = self.hop();
+        let last_start = match samples.len().checked_sub(self.fft_len) {
+            Some(last_start) => last_start,
+            None => return Vec::new(),
+        };
+
+        match &self.framing {
+            Framing::Fixed => (0..=last_start).step_by(hop).collect(),
+            Framing::Onset(onset_config) => {
+                let mut onsets = onset::detect_onsets(samples, onset_config)
+                    .into_iter()
+                    .peekable();
+                let mut starts = Vec::new();
+                let mut start = 0;
+
+                while start <= last_start {
+                    starts.push(start);
+
+                    while onsets.next_if(|onset| *onset <= start).is_some() {}
+                    start = onsets
+                        .next_if(|onset| *onset <= start + hop)
+                        .unwrap_or(start + hop);
+                }
+
+                starts
+            }
+        }
+    }
+}
+
+/// Why a [`SpectrogramConfig`] is invalid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    EmptyFft,
+    /// Frames would never advance, as the hop `fft_len - overlap` isn't positive
+    OverlapTooLarge {
+        fft_len: usize,
+        overlap: usize,
+    },
+    /// An overlap fraction wasn't at least 0 and less than 1
+    OverlapFractionOutOfRange,
+    /// `keep_bins` was 0, or more than the `fft_len / 2 + 1` bins that aren't mirrored
+    KeepBinsOutOfRange {
+        fft_len: usize,
+        keep_bins: usize,
+    },
+    /// [`Normalization::Log`] was used with [`AmplitudeScale::Db`], which would give NaN for
+    /// any bin more than 1dB below full scale
+    LogOfDecibels,
+    /// A [`mel::MelConfig`] had no mels
+    NoMels,
+    /// A [`mel::MelConfig`]'s `fmin` was negative or not below its `fmax`
+    MelRangeInvalid,
+    /// A [`mel::MelConfig`]'s `fmax` was above nyquist, `samplerate / 2`, where there are no bins
+    MelAboveNyquist {
+        samplerate: usize,
+    },
+    /// A [`filter::BandPassConfig`] cutoff wasn't above 0 and below nyquist, `samplerate / 2`,
+    /// where the filter would be unstable
+    CutoffOutOfRange {
+        samplerate: usize,
+    },
+    /// A [`filter::BandPassConfig`]'s low cut wasn't below its high cut, which would remove
+    /// every frequency
+    CutoffsCrossed,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyFft => write!(f, "fft_len must be greater than 0"),
+            Self::OverlapTooLarge { fft_len, overlap } => write!(
+                f,
+                "overlap ({overlap}) must be less than fft_len ({fft_len})"
+            ),
+            Self::OverlapFractionOutOfRange => {
+                write!(f, "overlap fraction must be at least 0 and less than 1")
+            }
+            Self::KeepBinsOutOfRange { fft_len, keep_bins } => write!(
+                f,
+                "keep_bins ({keep_bins}) must be between 1 and fft_len / 2 + 1 ({})",
+                fft_len / 2 + 1
+            ),
+            Self::LogOfDecibels => write!(
+                f,
+                "log normalization can only be used with linear amplitudes, not decibels"
+            ),
+            Self::NoMels => write!(f, "n_mels must be greater than 0"),
+            Self::MelRangeInvalid => {
+                write!(f, "mel fmin must be at least 0 and less than fmax")
+            }
+            Self::MelAboveNyquist { samplerate } => write!(
+                f,
+                "mel fmax must be at most half the samplerate ({}hz)",
+                *samplerate as f32 / 2.0
+            ),
+            Self::CutoffOutOfRange { samplerate } => write!(
+                f,
+                "band-pass cutoffs must be above 0hz and below half the samplerate ({}hz)",
+                *samplerate as f32 / 2.0
+            ),
+            Self::CutoffsCrossed => {
+                write!(f, "the band-pass low cut must be below its high cut")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The scale magnitudes are output on
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AmplitudeScale {
+    #[default]
+    Linear,
+    /// Decibels, `20 * log10(magnitude)`, with anything more than `top_db` below the loudest bin
+    /// of the frame raised up to that floor, like librosa's `amplitude_to_db`
+    Db { top_db: f32 },
+}
+
+impl AmplitudeScale {
+    fn apply<T: Float>(self, frame: &mut [T]) {
+        let Self::Db { top_db } = self else {
+            return;
+        };
+
+        // keeps silent bins from going to -inf
+        let epsilon = T::from_f32(1e-10).unwrap();
+        let twenty = T::from_f32(20.0).unwrap();
+        frame
+            .iter_mut()
+            .for_each(|value| *value = twenty * value.max(epsilon).log10());
+
+        let floor =
+            frame.iter().copied().fold(T::neg_infinity(), T::max) - T::from_f32(top_db).unwrap();
+        frame.iter_mut().for_each(|value| *value = value.max(floor));
+    }
+}
+
+/// How the magnitudes of each frame are scaled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Keep raw linear magnitudes
+    #[default]
+    None,
+    /// Scale each frame to unit length, so frames only differ by their shape rather than how
+    /// loud they are
+    L2,
+    /// Replace each magnitude `x` with `ln(1 + x)`, compressing the range between loud and quiet
+    /// bins
+    ///
+    /// This is only defined for linear magnitudes, so can't be used with [`AmplitudeScale::Db`]
+    Log,
+}
+
+impl Normalization {
+    fn apply<T: Float>(self, frame: &mut [T]) {
+        match self {
+            Self::None => {}
+            Self::L2 => {
+                let norm = frame
+                    .iter()
+                    .fold(T::zero(), |sum, value| sum + *value * *value)
+                    .sqrt();
+                // a silent frame has no direction, so is left as all zeros
+                if norm > T::zero() {
+                    frame.iter_mut().for_each(|value| *value = *value / norm);
+                }
+            }
+            Self::Log => frame.iter_mut().for_each(|value| *value = value.ln_1p()),
         }
     }
 }
 
-fn generate_hanning_window(size: usize) -> Vec<f32> {
-    let mut out = vec![0.0; size];
+/// How frames are positioned within the samples
+#[derive(Debug, Clone, Copy)]
+pub enum Framing {
+    /// A frame every `fft_len - overlap` samples
+    Fixed,
+    /// Like [`Framing::Fixed`], but a frame is started early at any onset found within the hop,
+    /// so that the same musical events line up with frame starts regardless of where the audio
+    /// began
+    ///
+    /// As frames are no longer evenly spaced, their start offsets need recording explicitly
+    Onset(onset::OnsetConfig),
+}
+
+/// Run `signal` through the spectrogram and back, returning the rms reconstruction error relative
+/// to the rms of `signal`
+///
+/// Samples too close to the edges to be covered by a window are ignored. This is useful as a
+/// canary for changes to the window or bin conventions, which will make the error jump.
+pub fn roundtrip_error(signal: &[f32], config: &SpectrogramConfig) -> f32 {
+    let generator: SpectrogramGenerator<f32> = SpectrogramGenerator::default();
+    let frame_starts = config.frame_starts(signal);
+    let frames = generator.stft(signal, config, &frame_starts);
+    let reconstructed = generator.inverse(&frames, config, &frame_starts);
+
+    // skip the first and last hop, which are only partially covered by a single window, and the
+    // zero padding past the end of `signal` that `pad_last` reconstructs
+    let hop = config.hop();
+    let end = signal.len().min(reconstructed.len()).saturating_sub(hop);
+    if end <= hop {
+        return 0.0;
+    }
+
+    let (error, power) = signal[hop..end].iter().zip(&reconstructed[hop..end]).fold(
+        (0.0, 0.0),
+        |(error, power), (original, reconstructed)| {
+            (
+                error + (original - reconstructed).powi(2),
+                power + original.powi(2),
+            )
+        },
+    );
+
+    match power > 0.0 {
+        true => (error / power).sqrt(),
+        false => error.sqrt(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLERATE: f32 = 16_000.0;
+
+    fn sine(hz: f32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (std::f32::consts::TAU * hz * i as f32 / SAMPLERATE).sin())
+            .collect()
+    }
+
+    /// A sine sweeping linearly from `from_hz` to `to_hz`
+    fn chirp(from_hz: f32, to_hz: f32, len: usize) -> Vec<f32> {
+        let duration = len as f32 / SAMPLERATE;
+        (0..len)
+            .map(|i| {
+                let t = i as f32 / SAMPLERATE;
+                let phase = from_hz * t + (to_hz - from_hz) * t * t / (2.0 * duration);
+                (std::f32::consts::TAU * phase).sin()
+            })
+            .collect()
+    }
+
+    fn config(fft_len: usize, overlap: usize) -> SpectrogramConfig {
+        SpectrogramConfig {
+            fft_len,
+            overlap,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn compensate_overlap_is_invariant_to_overlap() {
+        // 500hz lands exactly on bin 32, so every frame of the tone has the same spectrum
+        let tone = sine(500.0, 16_384);
+        let peaks = |overlap| {
+            let config = SpectrogramConfig {
+                compensate_overlap: true,
+                ..config(1024, overlap)
+            };
+            SpectrogramGenerator::<f32>::default()
+                .run(&tone, &config)
+                .iter()
+                .map(|frame| frame[32])
+                .collect::<Vec<_>>()
+        };
+
+        let (quarter, three_quarters) = (peaks(256), peaks(768));
+        for peak in quarter.iter().chain(&three_quarters) {
+            // a unit sine measures half its amplitude once the window's gain is removed
+            assert!((peak - 0.5).abs() < 1e-3, "peak was {peak}");
+        }
+        assert!(
+            (quarter[0] - three_quarters[0]).abs() < 1e-4,
+            "{} at 25% overlap vs {} at 75% overlap",
+            quarter[0],
+            three_quarters[0]
+        );
+    }
 
-    for i in 0..size {
-        out[i] = 0.5 * (1.0 - (std::f32::consts::TAU * (i as f32 / size as f32)).cos());
+    #[test]
+    fn roundtrip_error_is_small_for_a_sine() {
+        let error = roundtrip_error(&sine(440.0, 16_000), &config(1024, 768));
+        assert!(error < 0.01, "roundtrip error was {error}");
     }
 
-    out
+    #[test]
+    fn roundtrip_error_is_small_for_a_chirp() {
+        let error = roundtrip_error(&chirp(100.0, 4_000.0, 16_000), &config(1024, 768));
+        assert!(error < 0.01, "roundtrip error was {error}");
+    }
+
+    #[test]
+    fn roundtrip_error_handles_padded_short_input() {
+        let config = SpectrogramConfig {
+            pad_last: true,
+            ..config(1024, 768)
+        };
+        // shorter than a single frame, so the reconstruction is longer than the input
+        let error = roundtrip_error(&sine(440.0, 700), &config);
+        assert!(error.is_finite());
+    }
 }
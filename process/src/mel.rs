@@ -0,0 +1,115 @@
+use crate::{ConfigError, Float};
+
+/// A triangular mel filterbank applied to each frame, replacing its bins with
+/// `n_mels` outputs spread evenly over the mel scale between `fmin` and `fmax`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MelConfig {
+    pub n_mels: usize,
+    pub fmin: f32,
+    pub fmax: f32,
+    /// The samplerate of the audio, needed to know which frequency each bin covers
+    pub samplerate: usize,
+}
+
+/// A single triangular filter, as the weights of the bins starting at `start`
+#[derive(Debug)]
+pub(crate) struct MelFilter {
+    start: usize,
+    weights: Vec<f32>,
+}
+
+/// The key a filterbank is cached under
+///
+/// `fmin` and `fmax` are stored as their bits so the key can be hashed
+pub(crate) type MelKey = (usize, usize, usize, usize, u32, u32);
+
+impl MelConfig {
+    /// Check that the filterbank covers a real range of frequencies that the fft has bins for
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.n_mels == 0 {
+            return Err(ConfigError::NoMels);
+        }
+        // written so that a NaN on either side is rejected too
+        if !(self.fmin >= 0.0 && self.fmin < self.fmax) {
+            return Err(ConfigError::MelRangeInvalid);
+        }
+        if self.fmax > self.samplerate as f32 / 2.0 {
+            return Err(ConfigError::MelAboveNyquist {
+                samplerate: self.samplerate,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn key(&self, fft_len: usize, n_bins: usize) -> MelKey {
+        (
+            fft_len,
+            n_bins,
+            self.samplerate,
+            self.n_mels,
+            self.fmin.to_bits(),
+            self.fmax.to_bits(),
+        )
+    }
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// The filters of a filterbank over the first `n_bins` bins of an fft of length `fft_len`
+pub(crate) fn filterbank(fft_len: usize, n_bins: usize, config: &MelConfig) -> Vec<MelFilter> {
+    let bin_hz = config.samplerate as f32 / fft_len as f32;
+    let mel_min = hz_to_mel(config.fmin);
+    let mel_max = hz_to_mel(config.fmax);
+    // each filter rises from the previous filter's centre and falls to the next one's
+    let points = (0..config.n_mels + 2)
+        .map(|i| mel_to_hz(mel_min + (mel_max - mel_min) * i as f32 / (config.n_mels + 1) as f32))
+        .collect::<Vec<_>>();
+
+    points
+        .windows(3)
+        .map(|edges| {
+            let (left, centre, right) = (edges[0], edges[1], edges[2]);
+            let weights = (0..n_bins)
+                .map(|bin| {
+                    let hz = bin as f32 * bin_hz;
+                    let rising = (hz - left) / (centre - left);
+                    let falling = (right - hz) / (right - centre);
+                    rising.min(falling).max(0.0)
+                })
+                .collect::<Vec<_>>();
+
+            let start = weights.iter().position(|weight| *weight > 0.0).unwrap_or(0);
+            let end = weights
+                .iter()
+                .rposition(|weight| *weight > 0.0)
+                .map(|end| end + 1)
+                .unwrap_or(start);
+
+            MelFilter {
+                start,
+                weights: weights[start..end].to_vec(),
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn apply_filterbank<T: Float>(frame: &[T], filters: &[MelFilter]) -> Vec<T> {
+    filters
+        .iter()
+        .map(|filter| {
+            frame[filter.start..]
+                .iter()
+                .zip(&filter.weights)
+                .fold(T::zero(), |sum, (value, weight)| {
+                    sum + *value * T::from_f32(*weight).unwrap()
+                })
+        })
+        .collect()
+}
@@ -0,0 +1,58 @@
+/// Options for the energy based onset detector used by [`crate::Framing::Onset`]
+#[derive(Debug, Clone, Copy)]
+pub struct OnsetConfig {
+    /// The length in samples of the blocks energy is measured over
+    pub block_len: usize,
+    /// How many times more energy a block must have than the one before it to count as an onset
+    pub threshold: f32,
+    /// Blocks with less mean energy per sample than this are never onsets, so that noise in
+    /// near-silence doesn't trigger them
+    pub min_energy: f32,
+}
+
+impl Default for OnsetConfig {
+    fn default() -> Self {
+        Self {
+            block_len: 256,
+            threshold: 2.0,
+            min_energy: 1e-6,
+        }
+    }
+}
+
+/// Find the sample offsets of sudden jumps in energy
+///
+/// Onsets are always aligned to the start of a block, and two neighbouring blocks can't both be
+/// onsets
+pub fn detect_onsets<S: crate::Float>(samples: &[S], config: &OnsetConfig) -> Vec<usize> {
+    let block_len = config.block_len.max(1);
+    let energies = samples
+        .chunks(block_len)
+        .map(|block| {
+            block
+                .iter()
+                .map(|s| s.to_f32().unwrap().powi(2))
+                .sum::<f32>()
+                / block.len() as f32
+        })
+        .collect::<Vec<_>>();
+
+    let mut onsets: Vec<usize> = Vec::new();
+
+    for (index, pair) in energies.windows(2).enumerate() {
+        let (previous, current) = (pair[0], pair[1]);
+        let block = index + 1;
+
+        if current < config.min_energy || current <= previous * config.threshold {
+            continue;
+        }
+
+        if onsets.last() == Some(&((block - 1) * block_len)) {
+            continue;
+        }
+
+        onsets.push(block * block_len);
+    }
+
+    onsets
+}
@@ -0,0 +1,274 @@
+use crate::Float;
+
+/// Options for [`crate::SpectrogramGenerator::peaks_2d`]
+#[derive(Debug, Clone, Copy)]
+pub struct Peak2dConfig {
+    /// How many frames either side a peak must be the maximum of
+    pub time_radius: usize,
+    /// How many bins either side a peak must be the maximum of
+    pub freq_radius: usize,
+    /// How many frames either side are used to compute the adaptive threshold
+    pub threshold_radius: usize,
+    /// The percentile, in `0.0..=1.0`, of a bin's magnitudes within `threshold_radius` frames
+    /// that a peak in that bin must be above
+    pub threshold_percentile: f32,
+    /// Peaks must always be above this, so that silence doesn't produce peaks
+    pub min_magnitude: f32,
+}
+
+impl Default for Peak2dConfig {
+    fn default() -> Self {
+        Self {
+            time_radius: 10,
+            freq_radius: 10,
+            threshold_radius: 50,
+            threshold_percentile: 0.9,
+            min_magnitude: 1e-3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Peak<T> {
+    pub frame: usize,
+    pub bin: usize,
+    pub magnitude: T,
+}
+
+pub(crate) fn peaks_2d<T: Float>(spectrogram: &[Vec<T>], config: &Peak2dConfig) -> Vec<Peak<T>> {
+    let local_max = max_filter(spectrogram, config.time_radius, config.freq_radius);
+    let thresholds = adaptive_thresholds(spectrogram, config);
+    let min_magnitude = T::from_f32(config.min_magnitude).unwrap();
+
+    let mut peaks = Vec::new();
+
+    for (frame, (values, maxes)) in spectrogram.iter().zip(&local_max).enumerate() {
+        for (bin, (value, max)) in values.iter().zip(maxes).enumerate() {
+            let threshold = thresholds[frame][bin].max(min_magnitude);
+            if *value >= *max && *value > threshold {
+                peaks.push(Peak {
+                    frame,
+                    bin,
+                    magnitude: *value,
+                });
+            }
+        }
+    }
+
+    peaks
+}
+
+/// The maximum of every `±time_radius × ±freq_radius` neighbourhood, done as a max over
+/// frequency followed by a max over time
+fn max_filter<T: Float>(
+    spectrogram: &[Vec<T>],
+    time_radius: usize,
+    freq_radius: usize,
+) -> Vec<Vec<T>> {
+    let by_freq = spectrogram
+        .iter()
+        .map(|frame| {
+            (0..frame.len())
+                .map(|bin| {
+                    let range =
+                        bin.saturating_sub(freq_radius)..(bin + freq_radius + 1).min(frame.len());
+                    frame[range].iter().copied().fold(T::neg_infinity(), T::max)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    (0..by_freq.len())
+        .map(|frame| {
+            let range =
+                frame.saturating_sub(time_radius)..(frame + time_radius + 1).min(by_freq.len());
+            let bins = by_freq[frame].len();
+            (0..bins)
+                .map(|bin| {
+                    by_freq[range.clone()]
+                        .iter()
+                        .map(|frame| frame[bin])
+                        .fold(T::neg_infinity(), T::max)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The `threshold_percentile` of each bin's magnitudes within `threshold_radius` frames of
+/// each frame, so that quiet passages still produce peaks
+///
+/// Each bin's window is slid along one frame at a time, counting the frames in it by the rank
+/// of their magnitude, so this takes `O(frames × bins × log(frames))` whatever the radius
+fn adaptive_thresholds<T: Float>(spectrogram: &[Vec<T>], config: &Peak2dConfig) -> Vec<Vec<T>> {
+    let percentile = config.threshold_percentile.clamp(0.0, 1.0);
+    let n_frames = spectrogram.len();
+    let n_bins = spectrogram.first().map_or(0, Vec::len);
+    let mut thresholds = vec![vec![T::zero(); n_bins]; n_frames];
+
+    for bin in 0..n_bins {
+        let mut by_rank = (0..n_frames).collect::<Vec<_>>();
+        by_rank.sort_by(|a, b| {
+            spectrogram[*a][bin]
+                .partial_cmp(&spectrogram[*b][bin])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mut ranks = vec![0; n_frames];
+        for (rank, frame) in by_rank.iter().enumerate() {
+            ranks[*frame] = rank;
+        }
+
+        let mut window = RankCounts::new(n_frames);
+        let (mut start, mut end) = (0, 0);
+        for (frame, thresholds) in thresholds.iter_mut().enumerate() {
+            while end < (frame + config.threshold_radius + 1).min(n_frames) {
+                window.add(ranks[end], 1);
+                end += 1;
+            }
+            while start < frame.saturating_sub(config.threshold_radius) {
+                window.add(ranks[start], -1);
+                start += 1;
+            }
+
+            let index = ((end - start - 1) as f32 * percentile).round() as usize;
+            thresholds[bin] = spectrogram[by_rank[window.nth(index)]][bin];
+        }
+    }
+
+    thresholds
+}
+
+/// How many of each rank are in a window, as a fenwick tree so that both updating a count and
+/// finding the nth smallest rank are `O(log(ranks))`
+struct RankCounts {
+    tree: Vec<isize>,
+}
+
+impl RankCounts {
+    fn new(n_ranks: usize) -> Self {
+        Self {
+            tree: vec![0; n_ranks + 1],
+        }
+    }
+
+    fn add(&mut self, rank: usize, count: isize) {
+        let mut i = rank + 1;
+        while i < self.tree.len() {
+            self.tree[i] += count;
+            i += i & i.wrapping_neg();
+        }
+    }
+
+    /// The rank with `n` smaller ranks before it in the window, which has to have more than `n`
+    fn nth(&self, n: usize) -> usize {
+        let mut remaining = n as isize;
+        let mut position = 0;
+        let mut step = (self.tree.len() - 1).next_power_of_two();
+
+        while step > 0 {
+            let next = position + step;
+            if next < self.tree.len() && self.tree[next] <= remaining {
+                remaining -= self.tree[next];
+                position = next;
+            }
+            step /= 2;
+        }
+
+        position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Peak2dConfig {
+        Peak2dConfig {
+            time_radius: 2,
+            freq_radius: 2,
+            threshold_radius: 5,
+            threshold_percentile: 0.9,
+            min_magnitude: 1e-3,
+        }
+    }
+
+    fn positions(peaks: &[Peak<f32>]) -> Vec<(usize, usize)> {
+        peaks.iter().map(|peak| (peak.frame, peak.bin)).collect()
+    }
+
+    #[test]
+    fn only_the_largest_point_of_a_neighbourhood_is_a_peak() {
+        let mut spectrogram = vec![vec![0.0; 16]; 16];
+        spectrogram[8][4] = 1.0;
+        // within `freq_radius` of the larger spike
+        spectrogram[8][6] = 0.5;
+        // just outside both radii
+        spectrogram[11][9] = 0.5;
+
+        assert_eq!(
+            positions(&peaks_2d(&spectrogram, &config())),
+            vec![(8, 4), (11, 9)]
+        );
+    }
+
+    #[test]
+    fn thresholds_are_the_percentile_of_each_bin_around_a_frame() {
+        // bin 0 counts up by frame, and bin 1 counts down
+        let spectrogram = (0..11)
+            .map(|frame| vec![frame as f32, 10.0 - frame as f32])
+            .collect::<Vec<_>>();
+        let config = |threshold_radius, threshold_percentile| Peak2dConfig {
+            threshold_radius,
+            threshold_percentile,
+            ..config()
+        };
+
+        let median = adaptive_thresholds(&spectrogram, &config(10, 0.5));
+        assert!(median.iter().all(|bins| bins == &[5.0, 5.0]));
+
+        let thresholds = adaptive_thresholds(&spectrogram, &config(2, 0.9));
+        // the windows are cut short at either end
+        assert_eq!(thresholds[0], [2.0, 10.0]);
+        assert_eq!(thresholds[5], [7.0, 7.0]);
+        assert_eq!(thresholds[10], [10.0, 2.0]);
+    }
+
+    #[test]
+    fn thresholds_agree_with_sorting_each_window() {
+        let spectrogram = (0..40)
+            .map(|frame| {
+                (0..8)
+                    .map(|bin| ((frame * 7 + bin * 13) % 17) as f32)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let thresholds = adaptive_thresholds(&spectrogram, &config());
+        for (frame, thresholds) in thresholds.iter().enumerate() {
+            for (bin, threshold) in thresholds.iter().enumerate() {
+                let mut window = spectrogram[frame.saturating_sub(5)..(frame + 6).min(40)]
+                    .iter()
+                    .map(|frame| frame[bin])
+                    .collect::<Vec<_>>();
+                window.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let index = ((window.len() - 1) as f32 * 0.9).round() as usize;
+                assert_eq!(*threshold, window[index], "frame {frame} bin {bin}");
+            }
+        }
+    }
+
+    #[test]
+    fn quiet_passages_still_have_peaks() {
+        // a loud passage followed by one 100 times quieter, each with a spike over its background
+        let mut spectrogram = (0..40)
+            .map(|frame| vec![if frame < 20 { 1.0 } else { 0.01 }; 16])
+            .collect::<Vec<_>>();
+        spectrogram[8][5] = 10.0;
+        spectrogram[30][10] = 0.1;
+
+        assert_eq!(
+            positions(&peaks_2d(&spectrogram, &config())),
+            vec![(8, 5), (30, 10)]
+        );
+    }
+}
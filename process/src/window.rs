@@ -0,0 +1,59 @@
+/// The window applied to each frame before the fft
+///
+/// Windows with a wider main lobe smear each frequency over more bins, but leak less energy into
+/// distant bins through their side lobes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WindowFunction {
+    #[default]
+    Hann,
+    Hamming,
+    Blackman,
+    BlackmanHarris,
+    /// No windowing at all, which has the narrowest main lobe but the worst leakage
+    Rectangular,
+}
+
+impl WindowFunction {
+    /// The coefficients `a_k` of this window as a sum of cosines,
+    /// `w[n] = Σ (-1)^k a_k cos(2πkn / N)`
+    fn coefficients(self) -> &'static [f32] {
+        match self {
+            Self::Hann => &[0.5, 0.5],
+            Self::Hamming => &[0.54, 0.46],
+            Self::Blackman => &[0.42, 0.5, 0.08],
+            Self::BlackmanHarris => &[0.35875, 0.48829, 0.14128, 0.01168],
+            Self::Rectangular => &[1.0],
+        }
+    }
+
+    /// Generate this window with length `size`
+    ///
+    /// The periodic form (`symmetric = false`) divides by `size`, matching scipy's `sym=False`,
+    /// and is what spectral analysis (and so fingerprinting) expects, as consecutive windows then
+    /// overlap-add to a constant. The symmetric form divides by `size - 1`, so the last sample
+    /// mirrors the first, matching `sym=True`, which is more common for filter design.
+    pub fn generate(self, size: usize, symmetric: bool) -> Vec<f32> {
+        let coefficients = self.coefficients();
+        let period = match symmetric {
+            true => size.saturating_sub(1).max(1),
+            false => size,
+        };
+
+        (0..size)
+            .map(|i| {
+                let phase = std::f32::consts::TAU * (i as f32 / period as f32);
+                coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(k, a)| {
+                        let sign = match k % 2 {
+                            0 => 1.0,
+                            _ => -1.0,
+                        };
+                        sign * a * (k as f32 * phase).cos()
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,94 @@
+use std::path::Path;
+
+use image::{Rgb, RgbImage};
+
+/// Height in pixels of the per-frame marker strip drawn above the spectrogram
+const MARKER_HEIGHT: u32 = 12;
+
+const MATCHED_COLOUR: Rgb<u8> = Rgb([40, 200, 80]);
+const OTHER_COLOUR: Rgb<u8> = Rgb([200, 40, 40]);
+const UNMATCHED_COLOUR: Rgb<u8> = Rgb([60, 60, 60]);
+
+/// What the nearest neighbour of a single query frame turned out to be
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameMarker {
+    /// The nearest neighbour belonged to the top match
+    Matched,
+    /// The nearest neighbour belonged to some other song
+    Other,
+    /// No neighbours were found within the threshold
+    Unmatched,
+}
+
+/// Render `spectrogram` to a png, with time on the x-axis and frequency on the y-axis
+///
+/// If `markers` is given, a strip is drawn above the spectrogram with one marker per frame
+pub fn write_spectrogram(
+    path: &Path,
+    spectrogram: &[Vec<f32>],
+    markers: Option<&[FrameMarker]>,
+) -> image::ImageResult<()> {
+    let width = spectrogram.len() as u32;
+    let bins = spectrogram.first().map(|frame| frame.len()).unwrap_or(0) as u32;
+    let offset = match markers {
+        Some(_) => MARKER_HEIGHT,
+        None => 0,
+    };
+
+    // log scaling so that quiet detail is still visible next to loud peaks
+    let max = spectrogram
+        .iter()
+        .flatten()
+        .map(|v| v.ln_1p())
+        .fold(0.0f32, f32::max);
+
+    let mut image = RgbImage::new(width.max(1), (bins + offset).max(1));
+
+    for (x, frame) in spectrogram.iter().enumerate() {
+        for (bin, value) in frame.iter().enumerate() {
+            let scaled = match max > 0.0 {
+                true => value.ln_1p() / max,
+                false => 0.0,
+            };
+            // low frequencies at the bottom
+            let y = offset + bins - 1 - bin as u32;
+            image.put_pixel(x as u32, y, colourmap(scaled));
+        }
+    }
+
+    if let Some(markers) = markers {
+        for (x, marker) in markers.iter().enumerate().take(width as usize) {
+            let colour = match marker {
+                FrameMarker::Matched => MATCHED_COLOUR,
+                FrameMarker::Other => OTHER_COLOUR,
+                FrameMarker::Unmatched => UNMATCHED_COLOUR,
+            };
+            for y in 0..MARKER_HEIGHT {
+                image.put_pixel(x as u32, y, colour);
+            }
+        }
+    }
+
+    image.save(path)
+}
+
+/// Map a value in `0.0..=1.0` onto a black -> purple -> orange -> yellow ramp
+fn colourmap(value: f32) -> Rgb<u8> {
+    const STOPS: [[f32; 3]; 4] = [
+        [0.0, 0.0, 0.0],
+        [120.0, 28.0, 110.0],
+        [235.0, 105.0, 35.0],
+        [252.0, 255.0, 165.0],
+    ];
+
+    let value = value.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let index = (value.floor() as usize).min(STOPS.len() - 2);
+    let t = value - index as f32;
+    let (from, to) = (STOPS[index], STOPS[index + 1]);
+
+    Rgb([
+        (from[0] + (to[0] - from[0]) * t) as u8,
+        (from[1] + (to[1] - from[1]) * t) as u8,
+        (from[2] + (to[2] - from[2]) * t) as u8,
+    ])
+}
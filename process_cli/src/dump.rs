@@ -0,0 +1,66 @@
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// A json array of arrays, one per frame
+    Json,
+    /// A 2d little-endian f32 numpy array of shape `(frames, bins)`
+    Npy,
+    /// One line per frame, with bins separated by commas
+    Csv,
+}
+
+pub fn write_spectrogram(
+    writer: &mut impl Write,
+    spectrogram: &[Vec<f32>],
+    format: DumpFormat,
+) -> std::io::Result<()> {
+    match format {
+        DumpFormat::Json => {
+            serde_json::to_writer(&mut *writer, spectrogram)?;
+            writeln!(writer)
+        }
+        DumpFormat::Npy => write_npy(writer, spectrogram),
+        DumpFormat::Csv => {
+            for frame in spectrogram {
+                let line = frame
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                writeln!(writer, "{line}")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Write a version 1.0 `.npy` file, see
+/// <https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html>
+fn write_npy(writer: &mut impl Write, spectrogram: &[Vec<f32>]) -> std::io::Result<()> {
+    const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+
+    let bins = spectrogram.first().map(|frame| frame.len()).unwrap_or(0);
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        spectrogram.len(),
+        bins
+    );
+    // the header is padded with spaces and terminated with a newline so that the data
+    // starts on a 64 byte boundary
+    let unpadded = MAGIC.len() + 2 + header.len() + 1;
+    header.push_str(&" ".repeat(unpadded.next_multiple_of(64) - unpadded));
+    header.push('\n');
+
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+
+    for frame in spectrogram {
+        for value in frame {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use std::path::PathBuf;
+
+use tracing::{info, warn};
+
+use crate::{
+    connect_db, handle_file_with, output, resample, score_spectrogram, ProcessingArgs, QueryArgs,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct EvaluateArgs {
+    /// A file containing one json object per line, in the form
+    /// ```json
+    /// { "path": String, "expected_song_id": i64 }
+    /// ```
+    manifest: PathBuf,
+    /// The url to connect to the database
+    #[arg(long, short)]
+    db: String,
+    /// Make the program output a json dictionary with the results
+    #[arg(long, short, action = clap::ArgAction::SetTrue)]
+    json: bool,
+    /// Where to write the json results, either a file path or `-` for stdout; implies `--json`
+    #[arg(long, short, value_parser = output::parse_target)]
+    output: Option<output::OutputTarget>,
+    #[command(flatten)]
+    query: QueryArgs,
+    #[command(flatten)]
+    processing: ProcessingArgs,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ManifestEntry {
+    path: PathBuf,
+    expected_song_id: i64,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct EvaluateResult {
+    entries: Vec<EvaluateEntry>,
+    /// The fraction of queries where the top match was the expected song
+    precision_at_1: f64,
+    /// The mean of `1 / rank` of the expected song, counting it as 0 when it wasn't matched at all
+    mean_reciprocal_rank: f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct EvaluateEntry {
+    path: PathBuf,
+    expected_song_id: i64,
+    top_song_id: Option<i64>,
+    /// The 1-based rank of the expected song, if it was matched at all
+    rank: Option<usize>,
+}
+
+pub async fn evaluate(args: &EvaluateArgs) {
+    let manifest = std::fs::read_to_string(&args.manifest).expect("failed to read manifest");
+    let manifest = manifest
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str::<ManifestEntry>(line).expect("invalid manifest entry"))
+        .collect::<Vec<_>>();
+
+    let db = connect_db(&args.db, "plink-evaluate", args.query.max_concurrency).await;
+    let spect_gen: process::SpectrogramGenerator<f32> = process::SpectrogramGenerator::default();
+    let resamplers = resample::ResamplerPool::default();
+
+    let mut entries = Vec::with_capacity(manifest.len());
+
+    for entry in manifest {
+        let spectrogram =
+            match handle_file_with(&entry.path, &args.processing, &spect_gen, &resamplers) {
+                Ok(spectrogram) => spectrogram.frames,
+                Err(error) => {
+                    warn!(path = ?entry.path, %error, "skipping query that couldn't be processed");
+                    continue;
+                }
+            };
+        let scores = score_spectrogram(&db, &spectrogram, &args.query, usize::MAX, |_, _| {}).await;
+
+        let rank = scores
+            .ranked
+            .iter()
+            .position(|(song_id, _)| *song_id == entry.expected_song_id)
+            .map(|index| index + 1);
+        let top_song_id = scores.ranked.first().map(|(song_id, _)| *song_id);
+
+        match rank {
+            Some(1) => info!(path = ?entry.path, "matched"),
+            Some(rank) => {
+                warn!(path = ?entry.path, rank, ?top_song_id, "expected song was not the top match")
+            }
+            None => warn!(path = ?entry.path, ?top_song_id, "expected song was not matched"),
+        }
+
+        entries.push(EvaluateEntry {
+            path: entry.path,
+            expected_song_id: entry.expected_song_id,
+            top_song_id,
+            rank,
+        });
+    }
+
+    let n = entries.len().max(1) as f64;
+    let precision_at_1 = entries.iter().filter(|e| e.rank == Some(1)).count() as f64 / n;
+    let mean_reciprocal_rank = entries
+        .iter()
+        .map(|e| e.rank.map(|rank| 1.0 / rank as f64).unwrap_or(0.0))
+        .sum::<f64>()
+        / n;
+
+    info!(
+        queries = entries.len(),
+        precision_at_1, mean_reciprocal_rank, "evaluation finished"
+    );
+
+    if args.json || args.output.is_some() {
+        let result = EvaluateResult {
+            entries,
+            precision_at_1,
+            mean_reciprocal_rank,
+        };
+        args.output
+            .as_ref()
+            .unwrap_or(&output::OutputTarget::Stdout)
+            .write(&serde_json::to_string(&result).expect("failed to serialize json"))
+            .expect("failed to write output");
+    }
+}
@@ -1,18 +1,44 @@
-use clap::{arg, Parser};
+use clap::Parser;
+use database::SimilaritySearch;
 use process::SpectrogramConfig;
-use rubato::Resampler;
-use std::{fmt::Debug, path::PathBuf, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    path::PathBuf,
+    sync::Arc,
+};
 use symphonia::core::{
     audio::AudioBuffer, formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions,
     probe::Hint,
 };
-use tracing::{debug, info, instrument, trace, warn};
+use tracing::{debug, error, info, instrument, trace, warn};
+
+mod debug_image;
+mod dump;
+mod evaluate;
+mod output;
+mod resample;
+mod scoring;
+mod wav;
 
 const TARGET_SAMPLERATE_HZ: usize = 30_000;
 const SPECTROGRAM_CONFIG: &SpectrogramConfig = &process::SpectrogramConfig {
     fft_len: 1280,
     overlap: 320,
+    keep_bins: None,
+    framing: process::Framing::Fixed,
+    compensate_overlap: false,
+    freq_grouping: process::grouping::FreqGrouping::Linear,
+    window: process::window::WindowFunction::Hann,
+    symmetric_window: false,
+    pad_last: false,
+    amplitude_scale: process::AmplitudeScale::Linear,
+    normalization: process::Normalization::None,
 };
+/// Samples at or above this magnitude are assumed to be clipped
+const CLIPPING_LEVEL: f32 = 0.99;
+/// Warn about audio with more than this fraction of its samples clipped
+const CLIPPING_WARN_RATIO: f32 = 0.001;
 const DATE_FORMAT: &[time::format_description::BorrowedFormatItem<'static>] =
     time::macros::format_description!("[day]/[month]/[year]");
 
@@ -21,19 +47,33 @@ enum Command {
     /// Upload a single song to the database
     Upload {
         /// The path to this song's audio file
-        path: PathBuf,
+        ///
+        /// If several are given, like the two sides of a recording, they're joined end to end in
+        /// order and uploaded as one song, which records every path so `upload-bulk` skips each
+        /// of them
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
         /// The title of this song, including any artists
+        ///
+        /// Defaults to the title and artist tags of the audio file
         #[arg(long, short)]
-        title: String,
+        title: Option<String>,
         /// This song's `singer_id`
         #[arg(long, short)]
         singer_id: usize,
         #[arg(long, short)]
         db: String,
-        // TODO: figure out how to make clap parse the date
         /// The date this song was sung at, in `dd/mm/yyyy` format
-        #[arg(long, short)]
-        sung_at: Option<String>,
+        #[arg(long, value_parser = parse_date)]
+        sung_at: Option<time::Date>,
+        /// Also write the song's spectrogram to this png
+        #[arg(long)]
+        debug_image: Option<PathBuf>,
+        /// Also write the mono audio the spectrogram was generated from to this wav
+        #[arg(long)]
+        dump_audio: Option<PathBuf>,
+        #[command(flatten)]
+        processing: ProcessingArgs,
     },
     /// Upload many songs to the database
     UploadBulk {
@@ -63,36 +103,290 @@ enum Command {
         /// ```
         #[arg(long, short)]
         shell_script: String,
-        /// The url to connect to the database
-        #[arg(long, short)]
-        db: String,
+        /// The url to connect to the database, which isn't needed for `--dry-run`
+        #[arg(long, short, required_unless_present = "dry_run")]
+        db: Option<String>,
         /// The number of songs to upload simultaneously
-        #[arg(long, short, default_value_t = 64)]
+        #[arg(long, short, default_value_t = 64, value_parser = parse_at_least_one)]
         max_concurrency: usize,
+        /// Only run the shell script on each file and log what it parsed, without processing
+        /// any audio or touching the database
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        processing: ProcessingArgs,
     },
     /// See if a song matches any in the database
-    Discover {
-        /// The file to load
-        path: PathBuf,
+    Discover(DiscoverArgs),
+    /// Run discover over a set of queries with known matches and report how accurate it was
+    Evaluate(evaluate::EvaluateArgs),
+    /// Create the database schema, or bring an existing one up to date
+    ///
+    /// This needs running on databases made by older versions before new singers can be added,
+    /// as it moves the singer id sequence past the built in singers
+    Migrate {
         /// The url to connect to the database
         #[arg(long, short)]
         db: String,
-        /// The maximum distance to look for matching samples
-        #[arg(long, short, default_value_t = 200.0)]
-        max_distance: f64,
-        /// The maximum number of matching samples to look for
-        #[arg(long, short, default_value_t = 40)]
-        results_per: usize,
-        /// The number of samples to attempt to match simultaneously
-        #[arg(long, short, default_value_t = 200)]
-        max_concurrency: usize,
-        /// Make the program output a json dictionary with the results
-        #[arg(long, short, action = clap::ArgAction::SetTrue)]
-        json: bool,
-        /// How many potential matches should be included in the results?
-        #[arg(long, short, default_value_t = 10)]
-        n_matches: usize,
     },
+    /// Change the metadata of an uploaded song, leaving anything that isn't given as it is
+    Edit {
+        song_id: i64,
+        /// The url to connect to the database
+        #[arg(long, short)]
+        db: String,
+        /// The new title of the song, including any artists
+        #[arg(long, short)]
+        title: Option<String>,
+        /// The new `singer_id` of the song
+        #[arg(long, short)]
+        singer_id: Option<i16>,
+        /// The new date the song was sung at, in `dd/mm/yyyy` format
+        #[arg(long, value_parser = parse_date)]
+        sung_at: Option<time::Date>,
+        /// The new path of the song's audio file
+        #[arg(long)]
+        path: Option<PathBuf>,
+    },
+    /// List songs with incomplete records, such as a missing date, so they can be fixed
+    Audit {
+        /// The url to connect to the database
+        #[arg(long, short)]
+        db: String,
+    },
+    /// Show how many songs, singers and segments are stored
+    Stats {
+        /// The url to connect to the database
+        #[arg(long, short)]
+        db: String,
+    },
+    /// Delete songs that haven't been a discover result recently
+    Prune {
+        /// The url to connect to the database
+        #[arg(long, short)]
+        db: String,
+        /// Delete songs that haven't been matched within this long, e.g. `30d`, `12h`, `90m`
+        #[arg(long, short, value_parser = parse_duration)]
+        older_than: std::time::Duration,
+    },
+    /// Delete every song by a singer, e.g. because their songs were mislabeled
+    DeleteSinger {
+        /// The `singer_id` whose songs should be deleted, the singer itself is kept
+        singer_id: i16,
+        /// The url to connect to the database
+        #[arg(long, short)]
+        db: String,
+    },
+    /// Roughly reconstruct a stored song's audio from its spectrogram, to check what's in the
+    /// database
+    Preview {
+        /// The id of the song to reconstruct
+        id: i64,
+        /// The wav file to write
+        out: PathBuf,
+        /// The url to connect to the database
+        #[arg(long, short)]
+        db: String,
+    },
+    /// Find songs whose title contains some text
+    Search {
+        /// The text to look for, ignoring case
+        query: String,
+        /// The url to connect to the database
+        #[arg(long, short)]
+        db: String,
+        /// The most songs to list
+        #[arg(long, short, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Generate a song's spectrogram and write it to stdout without touching the database
+    Dump {
+        /// The path to the audio file
+        path: PathBuf,
+        /// The format to write the spectrogram in
+        #[arg(long, short, value_enum, default_value_t = dump::DumpFormat::Json)]
+        format: dump::DumpFormat,
+        #[command(flatten)]
+        processing: ProcessingArgs,
+    },
+}
+
+#[derive(Debug, clap::Args)]
+struct DiscoverArgs {
+    /// The files to load, or `-` to read from stdin
+    ///
+    /// With more than one file the json output is an array with a result per file
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+    /// The url to connect to the database
+    #[arg(long, short)]
+    db: String,
+    #[command(flatten)]
+    query: QueryArgs,
+    /// How to output the results, anything other than `text` is written to `--output`
+    #[arg(long, value_enum, default_value_t = output::OutputFormat::Text)]
+    format: output::OutputFormat,
+    /// Deprecated alias for `--format json`
+    #[arg(long, short, action = clap::ArgAction::SetTrue)]
+    json: bool,
+    /// Where to write the results, either a file path or `-` for stdout; implies `--format json`
+    /// if no other format is given
+    #[arg(long, short, value_parser = output::parse_target)]
+    output: Option<output::OutputTarget>,
+    /// How many potential matches should be included in the results?
+    #[arg(long, short, default_value_t = 10, value_parser = parse_at_least_one)]
+    n_matches: usize,
+    /// Write the query spectrogram to this png, marking which frames matched the top result
+    #[arg(long)]
+    debug_image: Option<PathBuf>,
+    /// Write the mono audio the query spectrogram was generated from to this wav
+    #[arg(long)]
+    dump_audio: Option<PathBuf>,
+    /// Write every query frame's neighbours to this file, one json object per line, before any
+    /// scoring is done
+    #[arg(long)]
+    dump_matches: Option<PathBuf>,
+    /// Include every query segment that matched each result in the output
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    verbose_matches: bool,
+    #[command(flatten)]
+    processing: ProcessingArgs,
+}
+
+/// Options controlling how a query spectrogram is matched against the database
+#[derive(Debug, clap::Args)]
+struct QueryArgs {
+    /// The maximum distance to look for matching samples
+    #[arg(long, short, default_value_t = 200.0)]
+    max_distance: f64,
+    /// Pick the maximum distance from the query instead of using `--max-distance`, as this
+    /// percentile (0.9 if not given) of the distances from a sample of its segments to their
+    /// nearest neighbours, e.g. `--auto-threshold` or `--auto-threshold=0.8`
+    #[arg(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "0.9",
+        value_parser = parse_fraction
+    )]
+    auto_threshold: Option<f64>,
+    /// The maximum number of matching samples to look for
+    #[arg(long, short, default_value_t = 40, value_parser = parse_at_least_one)]
+    results_per: usize,
+    /// Only query every nth sample of the query, which is faster for long queries but gives
+    /// each song fewer chances to match
+    #[arg(long, default_value_t = 1, value_parser = parse_at_least_one)]
+    sample_every: usize,
+    /// The number of samples to attempt to match simultaneously
+    #[arg(long, default_value_t = 200, value_parser = parse_at_least_one)]
+    max_concurrency: usize,
+    /// How many times to retry a sample's query if it fails, before giving up on that sample
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+    /// How long to wait before the first retry in milliseconds, doubling after each one
+    #[arg(long, default_value_t = 100)]
+    retry_backoff_ms: u64,
+    /// Give up on a sample's query if it takes longer than this many milliseconds, which counts
+    /// as a failure for `--retries`
+    #[arg(long)]
+    query_timeout_ms: Option<u64>,
+    /// Only keep track of scores for this many songs at once, bounding memory on huge databases
+    /// at the cost of slightly less accurate scores
+    #[arg(long)]
+    max_tracked_songs: Option<usize>,
+    /// How matching samples are turned into a score for each song, `--max-tracked-songs` isn't
+    /// used by `offset`
+    #[arg(long, value_enum, default_value_t = scoring::ScoringMethod::Rank)]
+    scoring: scoring::ScoringMethod,
+}
+
+/// Options controlling how audio is turned into a spectrogram
+///
+/// Songs are only comparable if they were uploaded and discovered with the same options
+#[derive(Debug, Clone, clap::Args)]
+struct ProcessingArgs {
+    /// The samplerate audio is resampled to before generating the spectrogram
+    #[arg(long, default_value_t = TARGET_SAMPLERATE_HZ)]
+    target_samplerate: usize,
+    /// The length of each fft, the database stores vectors of half this length so changing it
+    /// needs a matching schema
+    #[arg(long, default_value_t = SPECTROGRAM_CONFIG.fft_len)]
+    fft_len: usize,
+    /// How many samples consecutive frames overlap by, must be less than `--fft-len`
+    #[arg(long, default_value_t = SPECTROGRAM_CONFIG.overlap)]
+    overlap: usize,
+    /// Remove frequencies below this before generating the spectrogram, must be above 0 and
+    /// below `--high-cut-hz`
+    #[arg(long)]
+    low_cut_hz: Option<f32>,
+    /// Remove frequencies above this before generating the spectrogram, must be below half the
+    /// samplerate the audio ends up at, which with `--no-upsample` can be less than the target
+    #[arg(long)]
+    high_cut_hz: Option<f32>,
+    /// Keep audio sampled below the target samplerate at its original samplerate rather than
+    /// upsampling it
+    ///
+    /// Frequency bins then cover different frequencies, so the resulting spectrograms are only
+    /// comparable with ones generated at the same samplerate
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    no_upsample: bool,
+    /// Start frames early at detected onsets rather than always using a fixed hop
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    onset_framing: bool,
+    /// Zero pad a final frame covering the end of the audio rather than dropping samples that
+    /// don't fill a whole frame, useful for short clips
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pad_last: bool,
+    /// Only process up to this many seconds from the start of the audio
+    #[arg(long)]
+    max_seconds: Option<f64>,
+    /// The resampler used to convert audio to the target samplerate
+    #[arg(long, value_enum, default_value_t = resample::ResampleQuality::Balanced)]
+    resample_quality: resample::ResampleQuality,
+    /// Which channel of the audio to use, either `mix` to average every channel or the index of
+    /// a single channel starting from 0
+    #[arg(long, default_value = "mix", value_parser = parse_channel)]
+    channel: ChannelSelection,
+}
+
+/// Which channel of the audio a spectrogram is generated from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChannelSelection {
+    /// The average of every channel
+    Mix,
+    Index(usize),
+}
+
+fn parse_channel(value: &str) -> Result<ChannelSelection, String> {
+    match value {
+        "mix" => Ok(ChannelSelection::Mix),
+        index => index
+            .parse()
+            .map(ChannelSelection::Index)
+            .map_err(|_| format!("expected `mix` or a channel index, got `{index}`")),
+    }
+}
+
+impl ProcessingArgs {
+    fn spectrogram_config(&self) -> SpectrogramConfig {
+        SpectrogramConfig {
+            framing: match self.onset_framing {
+                true => process::Framing::Onset(Default::default()),
+                false => process::Framing::Fixed,
+            },
+            pad_last: self.pad_last,
+            fft_len: self.fft_len,
+            overlap: self.overlap,
+            ..SPECTROGRAM_CONFIG.clone()
+        }
+    }
+
+    fn band_pass(&self) -> process::filter::BandPassConfig {
+        process::filter::BandPassConfig {
+            low_cut_hz: self.low_cut_hz,
+            high_cut_hz: self.high_cut_hz,
+        }
+    }
 }
 
 #[tokio::main]
@@ -111,18 +405,24 @@ async fn main() {
 
     match Command::parse() {
         Command::Upload {
-            path,
+            paths,
             title,
             singer_id,
             db,
             sung_at,
+            debug_image,
+            dump_audio,
+            processing,
         } => {
             upload_song(
-                path,
-                &title,
+                &paths,
+                title.as_deref(),
                 singer_id,
                 &db,
-                sung_at.map(|date| time::Date::parse(&date, DATE_FORMAT).unwrap()),
+                sung_at,
+                debug_image.as_deref(),
+                dump_audio.as_deref(),
+                &processing,
             )
             .await
         }
@@ -131,165 +431,304 @@ async fn main() {
             shell_script,
             db,
             max_concurrency,
-        } => upload_bulk(directory, &shell_script, &db, max_concurrency).await,
-        Command::Discover {
-            path,
+            dry_run,
+            processing,
+        } => match (dry_run, db) {
+            (true, _) => dry_run_bulk(directory, &shell_script).await,
+            (false, Some(db)) => {
+                upload_bulk(directory, &shell_script, &db, max_concurrency, &processing).await
+            }
+            (false, None) => unreachable!("`--db` is required without `--dry-run`"),
+        },
+        Command::Discover(args) => discover_song(&args).await,
+        Command::Evaluate(args) => evaluate::evaluate(&args).await,
+        Command::Migrate { db } => {
+            let db = connect_db(&db, "plink-migrate", 1).await;
+            db.ensure_schema()
+                .await
+                .expect("failed to migrate database");
+            info!("database schema is up to date");
+        }
+        Command::Prune { db, older_than } => {
+            let db = connect_db(&db, "plink-prune", 1).await;
+            let deleted = db
+                .prune_songs(older_than)
+                .await
+                .expect("failed to prune songs");
+            info!(deleted, ?older_than, "pruned songs");
+        }
+        Command::Edit {
+            song_id,
             db,
-            max_distance,
-            results_per,
-            max_concurrency,
-            json,
-            n_matches,
+            title,
+            singer_id,
+            sung_at,
+            path,
         } => {
-            discover_song(
-                &path,
-                &db,
-                max_distance,
-                results_per,
-                max_concurrency,
-                json,
-                n_matches,
-            )
-            .await
+            let db = connect_db(&db, "plink-edit", 1).await;
+            let Some(song) = db.get_song(song_id).await.expect("failed to fetch song") else {
+                error!(song_id, "no song with this id exists");
+                return;
+            };
+            if let Some(singer_id) = singer_id {
+                let singer = db
+                    .get_singer(singer_id)
+                    .await
+                    .expect("failed to fetch from db");
+                if singer.is_none() {
+                    error!(singer_id, "no singer with this id exists");
+                    return;
+                }
+            }
+
+            let metadata = database::models::SongMetadata {
+                title: title.unwrap_or(song.metadata.title),
+                singer_id: singer_id.unwrap_or(song.metadata.singer_id),
+                date_first_sung: sung_at.or(song.metadata.date_first_sung),
+                local_path: match path {
+                    Some(path) => Some(path.to_str().unwrap().to_string()),
+                    None => song.metadata.local_path,
+                },
+                joined_paths: song.metadata.joined_paths,
+            };
+            let updated = db
+                .update_song_metadata(song_id, &metadata)
+                .await
+                .expect("failed to update song");
+            match updated {
+                true => info!(song_id, ?metadata, "updated song"),
+                false => error!(song_id, "song was deleted before it could be updated"),
+            }
+        }
+        Command::Audit { db } => {
+            let db = connect_db(&db, "plink-audit", 1).await;
+            let missing_date = db
+                .get_songs_missing_date()
+                .await
+                .expect("failed to fetch songs");
+            let without_segments = db
+                .count_songs_without_segments()
+                .await
+                .expect("failed to count songs");
+            info!(
+                missing_date = missing_date.len(),
+                without_segments, "incomplete songs"
+            );
+            for song in missing_date {
+                info!("{: >6}: {} has no date", song.id, song.metadata.title);
+            }
+        }
+        Command::Stats { db } => {
+            let db = connect_db(&db, "plink-stats", 1).await;
+            let songs = db.count_songs().await.expect("failed to count songs");
+            let singers = db
+                .get_singers()
+                .await
+                .expect("failed to fetch singers")
+                .len();
+            let segments = db.count_segments().await.expect("failed to count segments");
+            let estimated_segments = db
+                .estimated_segment_count()
+                .await
+                .expect("failed to estimate segment count");
+            info!(
+                songs,
+                singers,
+                segments,
+                ?estimated_segments,
+                "database stats"
+            );
+        }
+        Command::DeleteSinger { singer_id, db } => {
+            let db = connect_db(&db, "plink-delete-singer", 1).await;
+            let deleted = db
+                .delete_songs_by_singer(singer_id)
+                .await
+                .expect("failed to delete songs");
+            info!(deleted, singer_id, "deleted songs");
+        }
+        Command::Preview { id, out, db } => preview_song(id, &out, &db).await,
+        Command::Search { query, db, limit } => {
+            let db = connect_db(&db, "plink-search", 1).await;
+            let songs = db
+                .search_songs_by_title(&query, limit)
+                .await
+                .expect("failed to search songs");
+            info!(query, "found {} songs", songs.len());
+            for song in songs {
+                info!(
+                    "{: >6}: {} [singer_id={}]",
+                    song.id, song.metadata.title, song.metadata.singer_id
+                );
+            }
+        }
+        Command::Dump {
+            path,
+            format,
+            processing,
+        } => {
+            let spectrogram = match handle_file(&path, &processing) {
+                Ok(spectrogram) => spectrogram,
+                Err(error) => {
+                    error!(?path, %error, "failed to process file");
+                    return;
+                }
+            };
+            let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+            dump::write_spectrogram(&mut stdout, &spectrogram.frames, format)
+                .and_then(|_| std::io::Write::flush(&mut stdout))
+                .expect("failed to write spectrogram");
         }
     };
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn upload_song(
-    file: PathBuf,
-    title: &str,
+    files: &[PathBuf],
+    title: Option<&str>,
     singer_id: usize,
     db_url: &str,
     sung_at: Option<time::Date>,
+    debug_image: Option<&std::path::Path>,
+    dump_audio: Option<&std::path::Path>,
+    processing: &ProcessingArgs,
 ) {
-    let db = database::Database::connect(db_url)
+    let db = connect_db(db_url, "plink-upload", 1).await;
+
+    let singer = db
+        .get_singer(singer_id as i16)
         .await
-        .expect("failed to connect to db");
+        .expect("failed to fetch from db");
+    if singer.is_none() {
+        error!(singer_id, "no singer with this id exists");
+        return;
+    }
+
+    // canonicalized like `upload-bulk` does, so it can tell these files were already uploaded
+    let paths = match files
+        .iter()
+        .map(|file| {
+            file.canonicalize()
+                .map(|path| path.to_str().unwrap().to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(paths) => paths,
+        Err(error) => {
+            error!(paths = ?files, %error, "failed to find file");
+            return;
+        }
+    };
 
     let start = std::time::Instant::now();
-    let spectrogram = handle_file(&file, SPECTROGRAM_CONFIG);
+    let file = &files[0];
+    let spectrogram = match files.len() {
+        1 => handle_file(file, processing),
+        _ => handle_files_joined(files, processing),
+    };
+    let spectrogram = match spectrogram {
+        Ok(spectrogram) => spectrogram,
+        Err(error) => {
+            error!(paths = ?files, %error, "failed to process file");
+            return;
+        }
+    };
     let elapsed = start.elapsed();
     info!(?elapsed, "completed parse");
 
-    // debug_to_image(&spectrogram);
+    let title = match (title, spectrogram.tags.title()) {
+        (Some(title), _) => title.to_string(),
+        (None, Some(title)) => {
+            info!(title, "using title from file tags");
+            title
+        }
+        (None, None) => {
+            error!(path = ?file, "no `--title` was given and the file has no title tag");
+            return;
+        }
+    };
+
+    if let Some(debug_image_path) = debug_image {
+        debug_image::write_spectrogram(debug_image_path, &spectrogram.frames, None)
+            .expect("failed to write debug image");
+        info!(path = ?debug_image_path, "wrote debug image");
+    }
+    if let Some(dump_audio_path) = dump_audio {
+        write_audio(dump_audio_path, &spectrogram);
+    }
+
     let start = std::time::Instant::now();
     persist_to_db(
         db,
         spectrogram,
         &database::models::SongMetadata {
-            title: title.to_string(),
+            title,
             singer_id: singer_id as i16,
             date_first_sung: sung_at,
-            local_path: Some(file.to_str().unwrap().to_string()),
+            local_path: paths.first().cloned(),
+            joined_paths: paths[1..].to_vec(),
         },
-        SPECTROGRAM_CONFIG,
+        &processing.spectrogram_config(),
     )
     .await;
     let elapsed = start.elapsed();
     info!(?elapsed, "completed insert");
 }
 
-async fn upload_bulk(directory: PathBuf, executable: &str, db: &str, max_concurrency: usize) {
-    let db = database::Database::connect(db)
-        .await
-        .expect("failed to connect to database");
-
-    let mut handles = Vec::new();
-    let semaphore = Arc::new(tokio::sync::Semaphore::new(max_concurrency));
+async fn upload_bulk(
+    directory: PathBuf,
+    executable: &str,
+    db: &str,
+    max_concurrency: usize,
+    processing: &ProcessingArgs,
+) {
+    let processing = Arc::new(processing.clone());
+    let db = connect_db(db, "plink-upload-bulk", max_concurrency).await;
+    let spect_gen: process::SpectrogramGenerator<f32> = process::SpectrogramGenerator::default();
+    let resamplers = resample::ResamplerPool::default();
 
-    for dir in std::fs::read_dir(directory).expect("failed to read directory") {
-        let file = match dir {
-            Ok(file) => file,
-            Err(error) => {
-                warn!(?error, "failed to iterate file");
-                continue;
-            }
-        };
+    let files = files_in(directory);
+    let total = files.len();
+    // tasks finish out of order, so this is shared between them to report progress
+    let finished = Arc::new(std::sync::atomic::AtomicUsize::new(0));
 
-        if !file.file_type().expect("failed to get file type").is_file() {
-            debug!(?file, "skipping as not a file");
-            continue;
-        }
+    let mut handles = Vec::with_capacity(total);
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(max_concurrency));
 
+    for file in files {
         let task: tokio::task::JoinHandle<()> = {
             let semaphore = semaphore.clone();
             let db = db.clone();
             let shell_script = executable.to_string();
-            let full_file_path = file
-                .path()
-                .canonicalize()
-                .expect("failed to normalize path")
-                .to_str()
-                .unwrap()
-                .to_string();
+            let processing = processing.clone();
+            let finished = finished.clone();
+            let spect_gen = spect_gen.clone();
+            let resamplers = resamplers.clone();
 
             tokio::task::spawn(async move {
                 let _guard = semaphore
                     .acquire()
                     .await
                     .expect("faile to acquire semaphore");
-                let already_saved = db
-                    .song_already_saved(&full_file_path)
-                    .await
-                    .expect("failed to query db");
-
-                if already_saved {
-                    warn!(
-                        path = full_file_path,
-                        "skipping file as path is already in database"
-                    );
-                    return;
-                }
-
-                let command_output = tokio::process::Command::new("sh")
-                    .arg(shell_script)
-                    .arg(file.file_name())
-                    .stdout(std::process::Stdio::piped())
-                    .spawn()
-                    .expect("failed to spawn subprocess")
-                    .wait_with_output()
-                    .await
-                    .expect("failed to get command output");
-                let command_result: ParseResult =
-                    serde_json::from_slice(command_output.stdout.trim_ascii_end())
-                        .expect("failed to parse command output");
-
-                let metadata = match command_result {
-                    ParseResult::Parsed {
-                        title,
-                        date,
-                        singer_id,
-                    } => {
-                        let date = date.map(|date| {
-                            time::Date::parse(
-                                &format!("{:02}/{:02}/{}", date.day, date.month, date.year),
-                                DATE_FORMAT,
-                            )
-                            .expect("failed to parse date somehow")
-                        });
-                        debug!(title, ?date, "got song metadata");
-                        database::models::SongMetadata {
-                            title,
-                            singer_id: singer_id as i16,
-                            date_first_sung: date,
-                            local_path: Some(full_file_path),
-                        }
-                    }
-                    ParseResult::Error { error } => {
-                        warn!(?error, "failed to parse filename");
-                        return;
-                    }
-                };
+                upload_bulk_file(
+                    db,
+                    &file,
+                    &shell_script,
+                    &processing,
+                    &spect_gen,
+                    &resamplers,
+                )
+                .await;
 
-                let spectrogram = handle_file(&file.path(), SPECTROGRAM_CONFIG);
-                persist_to_db(db, spectrogram, &metadata, SPECTROGRAM_CONFIG).await;
+                let finished = finished.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+                info!(finished, total, path = ?file.path(), "finished file");
             })
         };
 
         handles.push(task);
     }
 
-    let join = futures::future::join_all(handles.into_iter()).await;
+    let join = futures::future::join_all(handles).await;
 
     let ok = join.iter().filter(|r| r.is_ok()).count();
     let err = join.iter().filter(|r| r.is_err()).count();
@@ -297,128 +736,980 @@ async fn upload_bulk(directory: PathBuf, executable: &str, db: &str, max_concurr
     info!(ok, err, "upload finished");
 }
 
-async fn discover_song(
-    path: &PathBuf,
-    db_url: &str,
-    max_distance: f64,
-    results_per_query: usize,
-    max_concurrency: usize,
-    output_json: bool,
-    n_matches: usize,
+/// Parse the metadata of a single file from [`upload_bulk`] and upload it, unless it's already
+/// in the database
+async fn upload_bulk_file(
+    db: database::Database,
+    file: &std::fs::DirEntry,
+    shell_script: &str,
+    processing: &ProcessingArgs,
+    spect_gen: &process::SpectrogramGenerator<f32>,
+    resamplers: &resample::ResamplerPool,
 ) {
-    info!("generating spectrogram");
-    let start = std::time::Instant::now();
-    let spectrogram = handle_file(path, SPECTROGRAM_CONFIG);
-    let spectrogram_time = start.elapsed();
-
-    let db = database::Database::connect(db_url)
+    let full_file_path = file
+        .path()
+        .canonicalize()
+        .expect("failed to normalize path")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let existing = db
+        .get_song_by_path(&full_file_path)
         .await
-        .expect("failed to connect to db");
-
-    let mut hashmap = std::collections::HashMap::new();
-    info!("querying database");
+        .expect("failed to query db");
 
-    let (send, mut recv) = tokio::sync::mpsc::unbounded_channel();
-    let semaphore = Arc::new(tokio::sync::Semaphore::new(max_concurrency));
+    if let Some(existing) = existing {
+        warn!(
+            path = full_file_path,
+            song_id = existing.id,
+            "skipping file as path is already in database"
+        );
+        return;
+    }
 
-    let start = std::time::Instant::now();
-    for sample in spectrogram {
-        let db = db.clone();
-        let send = send.clone();
-        let semaphore = semaphore.clone();
+    let metadata = match parse_filename(shell_script, &file.file_name()).await {
+        ParseResult::Parsed {
+            title,
+            date,
+            singer_id,
+        } => {
+            let date = date.map(|date| date.to_date());
+            debug!(title, ?date, "got song metadata");
+            database::models::SongMetadata {
+                title,
+                singer_id: singer_id as i16,
+                date_first_sung: date,
+                local_path: Some(full_file_path),
+                joined_paths: Vec::new(),
+            }
+        }
+        ParseResult::Error { error } => {
+            warn!(?error, "failed to parse filename");
+            return;
+        }
+    };
 
-        tokio::task::spawn(async move {
-            let _guard = semaphore
-                .acquire()
-                .await
-                .expect("failed to aquire semaphore");
-            let result = db
-                .find_similar_to(sample, max_distance, results_per_query as i64)
-                .await
-                .expect("failed to query database");
-            send.send(result).expect("failed to send to mpsc");
-        });
-    }
+    let spectrogram = match handle_file_with(&file.path(), processing, spect_gen, resamplers) {
+        Ok(spectrogram) => spectrogram,
+        Err(error) => {
+            warn!(path = ?file.path(), %error, "skipping file that couldn't be processed");
+            return;
+        }
+    };
+    persist_to_db(db, spectrogram, &metadata, &processing.spectrogram_config()).await;
+}
 
-    drop(send);
+/// Every file directly inside `directory`, skipping anything that isn't a file
+fn files_in(directory: PathBuf) -> Vec<std::fs::DirEntry> {
+    let mut files = Vec::new();
 
-    while let Some(result) = recv.recv().await {
-        let n = result.len();
-        for (index, (song_id, _sample_id, _distance)) in result.into_iter().enumerate() {
-            if !hashmap.contains_key(&song_id) {
-                hashmap.insert(song_id, 0);
+    for dir in std::fs::read_dir(directory).expect("failed to read directory") {
+        let file = match dir {
+            Ok(file) => file,
+            Err(error) => {
+                warn!(?error, "failed to iterate file");
+                continue;
             }
-            *hashmap.get_mut(&song_id).unwrap() += n - index;
+        };
+
+        if !file.file_type().expect("failed to get file type").is_file() {
+            debug!(?file, "skipping as not a file");
+            continue;
         }
+
+        files.push(file);
     }
-    let query_time = start.elapsed();
 
-    let mut top = hashmap.into_iter().collect::<Vec<_>>();
-    top.sort_by_key(|(_, v)| *v);
-    top.reverse();
+    files
+}
 
-    let singers = db.get_singers().await.expect("failed to fetch from db");
+/// Run the filename parsing script over every file in `directory`, logging what it found
+async fn dry_run_bulk(directory: PathBuf, executable: &str) {
+    let mut parsed = 0;
+    let mut unparsed = 0;
 
-    let mut result = DiscoverResult {
-        entries: Vec::with_capacity(n_matches),
-        timings: DiscoverTimings {
-            spectrogram: spectrogram_time,
-            query: query_time,
-        },
-    };
+    for file in files_in(directory) {
+        match parse_filename(executable, &file.file_name()).await {
+            ParseResult::Parsed {
+                title,
+                date,
+                singer_id,
+            } => {
+                let date = date.map(|date| date.to_date());
+                info!(path = ?file.path(), title, ?date, singer_id, "parsed filename");
+                parsed += 1;
+            }
+            ParseResult::Error { error } => {
+                warn!(path = ?file.path(), error, "failed to parse filename");
+                unparsed += 1;
+            }
+        }
+    }
 
-    for (song_id, score) in &top[..n_matches] {
-        let song_info = db
-            .get_song(*song_id)
-            .await
-            .expect("database error")
-            .unwrap();
-        let singer_id = song_info.metadata.singer_id;
-        let song_duration_ms = db
-            .get_song_duration_ms(*song_id)
-            .await
-            .expect("database error")
-            .unwrap();
+    info!(parsed, unparsed, "dry run finished");
+}
 
-        result.entries.push(DiscoverEntry {
-            song: song_info.into(),
-            singer_name: singers.get(&singer_id).unwrap().name.clone(),
-            score: *score,
-            song_duration_ms,
-        })
-    }
+/// Run the filename parsing script on `file_name`, see [`Command::UploadBulk`]
+async fn parse_filename(executable: &str, file_name: &std::ffi::OsStr) -> ParseResult {
+    let command_output = tokio::process::Command::new("sh")
+        .arg(executable)
+        .arg(file_name)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to spawn subprocess")
+        .wait_with_output()
+        .await
+        .expect("failed to get command output");
 
-    info!(timings=?result.timings, "completed");
-    info!("top {n_matches} matches");
-    for (index, entry) in result.entries.iter().enumerate() {
-        info!(
-            "{: >3}: {} [id={}]: score={}",
-            index + 1,
-            entry.song.title,
-            entry.song.id,
-            entry.score
+    serde_json::from_slice(command_output.stdout.trim_ascii_end())
+        .expect("failed to parse command output")
+}
+
+async fn discover_song(args: &DiscoverArgs) {
+    if args.paths.len() > 1
+        && (args.debug_image.is_some() || args.dump_audio.is_some() || args.dump_matches.is_some())
+    {
+        error!(
+            "`--debug-image`, `--dump-audio` and `--dump-matches` can only be used with a single file"
         );
+        return;
     }
 
-    if output_json {
-        println!(
-            "{}",
-            serde_json::to_string(&result).expect("failed to serialize json")
-        )
+    let db = connect_db(&args.db, "plink-discover", args.query.max_concurrency).await;
+    let spect_gen: process::SpectrogramGenerator<f32> = process::SpectrogramGenerator::default();
+    let resamplers = resample::ResamplerPool::default();
+
+    let mut results = Vec::with_capacity(args.paths.len());
+    for path in &args.paths {
+        if let Some(result) = discover_file(&db, &spect_gen, &resamplers, path, args).await {
+            results.push(DiscoverFileResult {
+                path: path.clone(),
+                result,
+            });
+        }
     }
+
+    let format = match args.format {
+        output::OutputFormat::Text if args.json || args.output.is_some() => {
+            output::OutputFormat::Json
+        }
+        format => format,
+    };
+    // a single file keeps the output it had before multiple files could be given
+    let single = args.paths.len() == 1;
+    let contents = match format {
+        output::OutputFormat::Text => return,
+        output::OutputFormat::Json => match single {
+            true => match results.first() {
+                Some(file) => serde_json::to_string(&file.result),
+                None => return,
+            },
+            false => serde_json::to_string(&results),
+        }
+        .expect("failed to serialize json"),
+        output::OutputFormat::Csv => {
+            let mut csv = match single {
+                true => "rank,song_id,title,singer,score",
+                false => "path,rank,song_id,title,singer,score",
+            }
+            .to_string();
+            for file in &results {
+                let path = file.path.to_string_lossy();
+                for (index, entry) in file.result.entries.iter().enumerate() {
+                    csv.push('\n');
+                    if !single {
+                        csv.push_str(&output::csv_field(&path));
+                        csv.push(',');
+                    }
+                    csv.push_str(&format!(
+                        "{},{},{},{},{}",
+                        index + 1,
+                        entry.song.id,
+                        output::csv_field(&entry.song.title),
+                        output::csv_field(&entry.singer_name),
+                        entry.score
+                    ));
+                }
+            }
+            csv
+        }
+    };
+    args.output
+        .as_ref()
+        .unwrap_or(&output::OutputTarget::Stdout)
+        .write(&contents)
+        .expect("failed to write output");
 }
 
-#[instrument(level = "trace")]
-fn handle_file(
-    filename: &PathBuf,
-    spectrogram_config: &process::SpectrogramConfig,
-) -> Vec<Vec<f32>> {
-    debug!("opening file");
+/// Match a single query file for [`discover_song`], logging the results
+///
+/// Returns `None` if the file couldn't be processed
+async fn discover_file<S: SimilaritySearch>(
+    db: &S,
+    spect_gen: &process::SpectrogramGenerator<f32>,
+    resamplers: &resample::ResamplerPool,
+    path: &PathBuf,
+    args: &DiscoverArgs,
+) -> Option<DiscoverResult> {
+    info!(?path, "generating spectrogram");
+    let start = std::time::Instant::now();
+    let processed = match handle_file_with(path, &args.processing, spect_gen, resamplers) {
+        Ok(spectrogram) => spectrogram,
+        Err(error) => {
+            error!(?path, %error, "failed to process file");
+            return None;
+        }
+    };
+    let spectrogram_time = start.elapsed();
+    if let Some(dump_audio_path) = &args.dump_audio {
+        write_audio(dump_audio_path, &processed);
+    }
+    let Spectrogram {
+        frames: spectrogram,
+        frame_starts,
+        samplerate,
+        clipping_ratio,
+        ..
+    } = processed;
+
+    let mut matched_segments: HashMap<i64, Vec<MatchedSegment>> = HashMap::new();
+    // the distinct stored segments of each song that any query frame matched
+    let mut matched_segment_indices: HashMap<i64, HashSet<i64>> = HashMap::new();
+    let mut dump_matches = args.dump_matches.as_ref().map(|path| {
+        std::io::BufWriter::new(
+            std::fs::File::create(path).expect("failed to create match dump file"),
+        )
+    });
+
+    info!("querying database");
+    let start = std::time::Instant::now();
+    let mut stats = MatchStats::default();
+    let QueryScores {
+        ranked: top,
+        nearest,
+        max_score,
+        max_distance,
+        n_queried,
+    } = score_spectrogram(
+        db,
+        &spectrogram,
+        &args.query,
+        args.n_matches,
+        |index, neighbours| {
+            stats.add(neighbours);
+            for neighbour in neighbours {
+                matched_segment_indices
+                    .entry(neighbour.song_id)
+                    .or_default()
+                    .insert(neighbour.segment_index);
+            }
+            if args.verbose_matches {
+                for neighbour in neighbours {
+                    matched_segments
+                        .entry(neighbour.song_id)
+                        .or_default()
+                        .push(MatchedSegment {
+                            query_segment_index: index,
+                            db_segment_index: neighbour.segment_index,
+                            distance: neighbour.distance,
+                        });
+                }
+            }
+            if let Some(writer) = &mut dump_matches {
+                let line = FrameMatches {
+                    index,
+                    start_ms: frame_starts[index] as f64 / samplerate as f64 * 1000.0,
+                    neighbours: neighbours
+                        .iter()
+                        .map(|neighbour| Neighbour {
+                            song_id: neighbour.song_id,
+                            segment_index: neighbour.segment_index,
+                            distance: neighbour.distance,
+                        })
+                        .collect(),
+                };
+                serde_json::to_writer(&mut *writer, &line)
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| std::io::Write::write_all(writer, b"\n"))
+                    .expect("failed to write match dump");
+            }
+        },
+    )
+    .await;
+    let query_time = start.elapsed();
+
+    if let Some(mut writer) = dump_matches {
+        std::io::Write::flush(&mut writer).expect("failed to write match dump");
+    }
+
+    if let Some(debug_image_path) = &args.debug_image {
+        let top_song_id = top.first().map(|(song_id, _)| *song_id);
+        let markers = nearest
+            .iter()
+            .map(|song_id| match song_id {
+                None => debug_image::FrameMarker::Unmatched,
+                Some(_) if *song_id == top_song_id => debug_image::FrameMarker::Matched,
+                Some(_) => debug_image::FrameMarker::Other,
+            })
+            .collect::<Vec<_>>();
+        debug_image::write_spectrogram(debug_image_path, &spectrogram, Some(&markers))
+            .expect("failed to write debug image");
+        info!(path = ?debug_image_path, "wrote debug image");
+    }
+
+    let mut result = DiscoverResult {
+        entries: collect_entries(
+            db,
+            &top[..top.len().min(args.n_matches)],
+            max_score,
+            &matched_segment_indices,
+            &database::models::SpectrogramParams {
+                samplerate: samplerate as i32,
+                fft_size: args.processing.fft_len as i32,
+                fft_overlap: args.processing.overlap as i32,
+            },
+        )
+        .await,
+        clipping_ratio,
+        stats: MatchStats {
+            segments_total: n_queried,
+            max_distance,
+            ..stats
+        },
+        timings: DiscoverTimings {
+            spectrogram: spectrogram_time,
+            query: query_time,
+        },
+    };
+
+    if args.verbose_matches {
+        for entry in &mut result.entries {
+            let mut matches = matched_segments.remove(&entry.song.id).unwrap_or_default();
+            // frames are matched concurrently, so arrive in whatever order their queries finish
+            matches.sort_by_key(|matched| (matched.query_segment_index, matched.db_segment_index));
+            entry.matches = Some(matches);
+        }
+    }
+
+    info!(timings=?result.timings, "completed");
+    info!(
+        max_distance = result.stats.max_distance,
+        distance = ?result.stats.distance,
+        "{} of {} segments matched",
+        result.stats.segments_with_matches,
+        result.stats.segments_total
+    );
+    match result.entries.len() {
+        0 => info!("no matches found"),
+        n_matches => info!("top {} matches", n_matches),
+    }
+    for (index, entry) in result.entries.iter().enumerate() {
+        info!(
+            "{: >3}: {} [id={}]: score={} confidence={:.3} matched {} of {} segments",
+            index + 1,
+            entry.song.title,
+            entry.song.id,
+            entry.score,
+            entry.confidence,
+            entry.matched_segment_count,
+            entry.segment_count
+        );
+    }
+
+    Some(result)
+}
+
+/// Parse a date in `dd/mm/yyyy` format
+fn parse_date(value: &str) -> Result<time::Date, String> {
+    time::Date::parse(value, DATE_FORMAT)
+        .map_err(|error| format!("{error}, expected a date in dd/mm/yyyy format"))
+}
+
+/// Parse a count that has to be at least 1, such as a number of concurrent tasks, which would
+/// otherwise never be able to make progress
+fn parse_at_least_one(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(count) => Ok(count),
+        Err(error) => Err(format!("{error}")),
+    }
+}
+
+/// Parse a fraction between 0 and 1 inclusive, such as a percentile
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.parse() {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        Ok(_) => Err("must be between 0 and 1".to_string()),
+        Err(error) => Err(format!("{error}")),
+    }
+}
+
+/// Parse a duration made of a number and a unit out of `s`, `m`, `h` or `d`
+fn parse_duration(value: &str) -> Result<std::time::Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(|| format!("missing unit in `{value}`, expected one of s, m, h or d"))?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid number in `{value}`"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => {
+            return Err(format!(
+                "unknown unit `{unit}`, expected one of s, m, h or d"
+            ))
+        }
+    };
+
+    Ok(std::time::Duration::from_secs(amount * seconds))
+}
+
+/// Connect to the database, identifying the connections as `application_name` to postgres
+///
+/// `max_connections` should be at least the number of queries that are run concurrently, so that
+/// tasks don't time out waiting for a connection
+async fn connect_db(
+    url: &str,
+    application_name: &str,
+    max_connections: usize,
+) -> database::Database {
+    database::Database::connect_with_config(
+        url,
+        &database::DatabaseConfig {
+            application_name: Some(application_name.to_string()),
+            max_connections: max_connections.max(1) as u32,
+            ..Default::default()
+        },
+    )
+    .await
+    .expect("failed to connect to db")
+}
+
+/// Look up the details of each `(song_id, score)` match, marking each song as matched
+///
+/// Each entry's confidence is its score as a fraction of `max_score`, and its matched segment
+/// count is the number of its segments in `matched_segment_indices`
+///
+/// Warns about any song stored with different parameters to the query's `params`, as their
+/// spectrograms aren't really comparable
+async fn collect_entries<S: SimilaritySearch>(
+    db: &S,
+    top: &[(i64, usize)],
+    max_score: usize,
+    matched_segment_indices: &HashMap<i64, HashSet<i64>>,
+    params: &database::models::SpectrogramParams,
+) -> Vec<DiscoverEntry> {
+    let singers = db.get_singers().await.expect("failed to fetch from db");
+    let song_ids = top.iter().map(|(song_id, _)| *song_id).collect::<Vec<_>>();
+    let mut songs = db
+        .get_songs_bulk(&song_ids)
+        .await
+        .expect("failed to fetch from db");
+    let segment_stats = db
+        .get_segment_stats_bulk(&song_ids)
+        .await
+        .expect("failed to fetch from db");
+    db.touch_songs(&song_ids).await.expect("database error");
+    let mut entries = Vec::with_capacity(top.len());
+
+    for (song_id, score) in top {
+        let song_info = songs.remove(song_id).unwrap();
+        let singer_id = song_info.metadata.singer_id;
+        if let Some(song_params) = song_info.params.filter(|song_params| song_params != params) {
+            warn!(
+                song_id,
+                ?song_params,
+                query_params = ?params,
+                "song was stored with different spectrogram parameters"
+            );
+        }
+        // every matched song has segments, so this is only missing if it was just deleted
+        let stats = segment_stats.get(song_id).copied().unwrap_or_default();
+
+        entries.push(DiscoverEntry {
+            song: song_info.into(),
+            singer_name: singers.get(&singer_id).unwrap().name.clone(),
+            score: *score,
+            confidence: *score as f64 / max_score.max(1) as f64,
+            song_duration_ms: stats.duration_ms,
+            segment_count: stats.segment_count,
+            matched_segment_count: matched_segment_indices
+                .get(song_id)
+                .map_or(0, |indices| indices.len()),
+            matches: None,
+        })
+    }
+
+    entries
+}
+
+/// The result of matching a single query spectrogram against the database
+struct QueryScores {
+    /// `(song_id, score)` for the best matching songs, best match first
+    ranked: Vec<(i64, usize)>,
+    /// The song id of each query frame's nearest neighbour, `None` for frames without any or
+    /// that weren't queried because of `--sample-every`
+    nearest: Vec<Option<i64>>,
+    /// The highest score a single song could have got, if it was every neighbour of every
+    /// queried frame
+    max_score: usize,
+    /// The maximum distance neighbours were looked for within, which is picked from the query
+    /// with `--auto-threshold`
+    max_distance: f64,
+    /// How many frames were queried, which is fewer than there are with `--sample-every`
+    n_queried: usize,
+}
+
+/// Match every frame of `spectrogram`, or every `--sample-every`th one, against the database,
+/// calling `on_frame` with each frame's index and neighbours as they arrive
+async fn score_spectrogram<S: SimilaritySearch>(
+    db: &S,
+    spectrogram: &[Vec<f32>],
+    query: &QueryArgs,
+    n_matches: usize,
+    mut on_frame: impl FnMut(usize, &[database::models::SimilarMatch]),
+) -> QueryScores {
+    let mut scores = scoring::ScoreBoard::new(query.max_tracked_songs);
+    let mut offsets = scoring::OffsetHistogram::new();
+
+    // tasks hold their permit until their result is sent, so once this fills up no new queries
+    // are started until the results have been scored
+    let (send, mut recv) = tokio::sync::mpsc::channel(query.max_concurrency);
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(query.max_concurrency));
+    let results_per_query = query.results_per;
+    let policy = QueryPolicy::from_args(query);
+    let max_distance = match query.auto_threshold {
+        Some(percentile) => match estimate_threshold(db, spectrogram, percentile, policy).await {
+            Some(threshold) => {
+                info!(
+                    threshold,
+                    percentile, "picked maximum distance from the query"
+                );
+                threshold
+            }
+            None => {
+                warn!(
+                    max_distance = query.max_distance,
+                    "no neighbours found to pick a maximum distance from, using `--max-distance`"
+                );
+                query.max_distance
+            }
+        },
+        None => query.max_distance,
+    };
+
+    // skipped frames keep their index, so offsets between frames are still correct
+    let mut n_queried = 0;
+    for (index, sample) in spectrogram
+        .iter()
+        .cloned()
+        .enumerate()
+        .step_by(query.sample_every)
+    {
+        n_queried += 1;
+        let db = db.clone();
+        let send = send.clone();
+        let semaphore = semaphore.clone();
+
+        tokio::task::spawn(async move {
+            let _guard = semaphore
+                .acquire()
+                .await
+                .expect("failed to aquire semaphore");
+            let result = query_sample(&db, &sample, max_distance, results_per_query, policy)
+                .await
+                .unwrap_or_else(|error| {
+                    error!(index, %error, "failed to query database, treating sample as unmatched");
+                    Vec::new()
+                });
+            send.send((index, result))
+                .await
+                .expect("failed to send to mpsc");
+        });
+    }
+
+    drop(send);
+
+    let mut nearest = vec![None; spectrogram.len()];
+
+    while let Some((frame_index, result)) = recv.recv().await {
+        on_frame(frame_index, &result);
+        nearest[frame_index] = result.first().map(|found| found.song_id);
+        match query.scoring {
+            scoring::ScoringMethod::Rank => {
+                let n = result.len();
+                for (index, found) in result.into_iter().enumerate() {
+                    scores.add(found.song_id, n - index);
+                }
+            }
+            scoring::ScoringMethod::Offset => {
+                for found in result {
+                    offsets.add(found.song_id, frame_index, found.segment_index);
+                }
+            }
+            scoring::ScoringMethod::Distance => {
+                for found in result {
+                    scores.add(
+                        found.song_id,
+                        scoring::distance_score(found.distance, max_distance),
+                    );
+                }
+            }
+        }
+    }
+
+    let (ranked, max_score) = match query.scoring {
+        // each frame's neighbours are scored `n, n - 1, ..., 1`
+        scoring::ScoringMethod::Rank => (
+            scores.top(n_matches),
+            n_queried * results_per_query * (results_per_query + 1) / 2,
+        ),
+        scoring::ScoringMethod::Offset => (offsets.top(n_matches), n_queried),
+        scoring::ScoringMethod::Distance => (
+            scores.top(n_matches),
+            n_queried * results_per_query * scoring::DISTANCE_SCORE_SCALE,
+        ),
+    };
+
+    QueryScores {
+        ranked,
+        nearest,
+        max_score,
+        max_distance,
+        n_queried,
+    }
+}
+
+/// How many segments of the query are used to pick a maximum distance for `--auto-threshold`
+const AUTO_THRESHOLD_SAMPLES: usize = 32;
+
+/// The `percentile` of the distances from evenly spread segments of `spectrogram` to their
+/// nearest neighbours, or `None` if none of them had any
+async fn estimate_threshold<S: SimilaritySearch>(
+    db: &S,
+    spectrogram: &[Vec<f32>],
+    percentile: f64,
+    policy: QueryPolicy,
+) -> Option<f64> {
+    let step = spectrogram.len().div_ceil(AUTO_THRESHOLD_SAMPLES).max(1);
+    let mut distances = Vec::new();
+
+    for (index, sample) in spectrogram.iter().enumerate().step_by(step) {
+        match query_sample(db, sample, f64::INFINITY, 1, policy).await {
+            Ok(result) => distances.extend(result.first().map(|found| found.distance)),
+            Err(error) => warn!(index, %error, "failed to query database, skipping sample"),
+        }
+    }
+
+    if distances.is_empty() {
+        return None;
+    }
+    distances.sort_by(f64::total_cmp);
+    let index = ((distances.len() - 1) as f64 * percentile).round() as usize;
+    Some(distances[index])
+}
+
+/// How the query for each sample is retried and timed out
+#[derive(Debug, Clone, Copy)]
+struct QueryPolicy {
+    retries: u32,
+    backoff: std::time::Duration,
+    timeout: Option<std::time::Duration>,
+}
+
+impl QueryPolicy {
+    fn from_args(query: &QueryArgs) -> Self {
+        Self {
+            retries: query.retries,
+            backoff: std::time::Duration::from_millis(query.retry_backoff_ms),
+            timeout: query.query_timeout_ms.map(std::time::Duration::from_millis),
+        }
+    }
+}
+
+/// Find up to `limit` neighbours of `sample` within `max_distance`, following `policy`
+async fn query_sample<S: SimilaritySearch>(
+    db: &S,
+    sample: &[f32],
+    max_distance: f64,
+    limit: usize,
+    policy: QueryPolicy,
+) -> Result<Vec<database::models::SimilarMatch>, QueryError<S::Error>> {
+    retry(policy.retries, policy.backoff, || {
+        let query = db.find_similar(sample.to_vec(), max_distance, limit as i64);
+        async move {
+            match policy.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, query)
+                    .await
+                    .map_err(|_| QueryError::TimedOut(timeout))?
+                    .map_err(QueryError::Database),
+                None => query.await.map_err(QueryError::Database),
+            }
+        }
+    })
+    .await
+}
+
+/// Why a single sample's query failed
+#[derive(Debug)]
+enum QueryError<E> {
+    Database(E),
+    TimedOut(std::time::Duration),
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for QueryError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Database(error) => write!(f, "{error}"),
+            Self::TimedOut(timeout) => write!(f, "query timed out after {timeout:?}"),
+        }
+    }
+}
+
+/// Run `f` until it succeeds, retrying up to `retries` times with exponential backoff starting
+/// at `backoff`, returning the last error if it never does
+async fn retry<T, E: std::fmt::Display, Fut: std::future::Future<Output = Result<T, E>>>(
+    retries: u32,
+    mut backoff: std::time::Duration,
+    mut f: impl FnMut() -> Fut,
+) -> Result<T, E> {
+    let mut attempt = 0;
+
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < retries => {
+                attempt += 1;
+                warn!(%error, attempt, ?backoff, "query failed, retrying");
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+async fn preview_song(song_id: i64, out: &std::path::Path, db_url: &str) {
+    let db = connect_db(db_url, "plink-preview", 1).await;
+    let segments = db
+        .get_song_segments(song_id)
+        .await
+        .expect("failed to fetch segments");
+
+    if segments.is_empty() {
+        warn!(song_id, "song has no stored segments");
+        return;
+    }
+
+    // songs stored before their parameters were recorded were all generated with the defaults
+    let params = db
+        .get_song(song_id)
+        .await
+        .expect("failed to fetch from db")
+        .and_then(|song| song.params);
+    let (samplerate, config) = match params {
+        Some(params) => (
+            params.samplerate as usize,
+            SpectrogramConfig {
+                fft_len: params.fft_size as usize,
+                overlap: params.fft_overlap as usize,
+                ..SPECTROGRAM_CONFIG.clone()
+            },
+        ),
+        None => (TARGET_SAMPLERATE_HZ, SPECTROGRAM_CONFIG.clone()),
+    };
+
+    // frames can have been skipped or started early at onsets, so each is placed by when it was
+    // stored as starting, which is only to the millisecond, so the exact offset of a fixed frame
+    // is used whenever it agrees
+    let hop = config.hop();
+    let to_ms = |offset: usize| (offset as f64 / samplerate as f64 * 1000.0) as i64;
+    let frame_starts = segments
+        .iter()
+        .map(|segment| match segment.sample_index as usize * hop {
+            start if to_ms(start) == segment.start_ts_ms => start,
+            _ => (segment.start_ts_ms as f64 / 1000.0 * samplerate as f64).round() as usize,
+        })
+        .collect::<Vec<_>>();
+    let spectrogram = segments
+        .into_iter()
+        .map(|segment| segment.sample)
+        .collect::<Vec<_>>();
+
+    let spect_gen: process::SpectrogramGenerator<f32> = process::SpectrogramGenerator::default();
+    let mut samples = spect_gen.reconstruct_fast(&spectrogram, &config, &frame_starts);
+
+    // the reconstruction isn't at any meaningful volume, so normalize it
+    let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+    if peak > 0.0 {
+        samples.iter_mut().for_each(|s| *s *= 0.9 / peak);
+    }
+
+    wav::write_mono(out, &samples, samplerate).expect("failed to write wav");
+    info!(song_id, path = ?out, "wrote preview");
+}
+
+/// Write the audio `spectrogram` was generated from to a wav at `path`, for `--dump-audio`
+fn write_audio(path: &std::path::Path, spectrogram: &Spectrogram) {
+    wav::write_mono(path, &spectrogram.audio, spectrogram.samplerate)
+        .expect("failed to write audio");
+    info!(?path, "wrote audio");
+}
+
+fn handle_file(
+    filename: &PathBuf,
+    processing: &ProcessingArgs,
+) -> Result<Spectrogram, HandleFileError> {
+    handle_file_with(
+        filename,
+        processing,
+        &process::SpectrogramGenerator::default(),
+        &resample::ResamplerPool::default(),
+    )
+}
+
+/// Like [`handle_file`], but reusing `spect_gen` and `resamplers` to avoid replanning ffts and
+/// resamplers for every file
+#[instrument(level = "trace", skip(spect_gen, resamplers))]
+fn handle_file_with(
+    filename: &PathBuf,
+    processing: &ProcessingArgs,
+    spect_gen: &process::SpectrogramGenerator<f32>,
+    resamplers: &resample::ResamplerPool,
+) -> Result<Spectrogram, HandleFileError> {
+    let decoded = decode_file(filename, processing)?;
+    let clipping_ratio = decoded.clipping_ratio();
+    let Some(mono) = decoded.mono else {
+        return Ok(Spectrogram {
+            frames: Vec::new(),
+            frame_starts: Vec::new(),
+            samplerate: decoded.samplerate,
+            clipping_ratio,
+            tags: decoded.tags,
+            audio: Vec::new(),
+        });
+    };
+
+    let (resampled, samplerate) = resample_audio(mono, decoded.samplerate, processing, resamplers)?;
+    spectrogram_from(
+        resampled,
+        samplerate,
+        clipping_ratio,
+        decoded.tags,
+        processing,
+        spect_gen,
+    )
+}
+
+/// Like [`handle_file`], but decoding every one of `filenames` and joining them end to end
+/// before generating a single spectrogram, so frames carry on across the joins
+///
+/// The tags are taken from the first file
+fn handle_files_joined(
+    filenames: &[PathBuf],
+    processing: &ProcessingArgs,
+) -> Result<Spectrogram, HandleFileError> {
+    let spect_gen = process::SpectrogramGenerator::default();
+    let resamplers = resample::ResamplerPool::default();
+
+    let mut joined = Vec::new();
+    let mut joined_samplerate = None;
+    let mut tags = None;
+    let (mut n_samples, mut n_clipped) = (0, 0);
+    for filename in filenames {
+        let decoded = decode_file(filename, processing)?;
+        n_samples += decoded.n_samples;
+        n_clipped += decoded.n_clipped;
+        tags.get_or_insert(decoded.tags);
+        let Some(mono) = decoded.mono else {
+            continue;
+        };
+
+        let (resampled, samplerate) =
+            resample_audio(mono, decoded.samplerate, processing, &resamplers)?;
+        // files only end up at different samplerates when `--no-upsample` kept one of them
+        if let Some(expected) = joined_samplerate {
+            if samplerate != expected {
+                return Err(HandleFileError::SampleRateMismatch {
+                    path: filename.clone(),
+                    samplerate,
+                    expected,
+                });
+            }
+        }
+        joined_samplerate = Some(samplerate);
+        debug!(path = ?filename, offset = joined.len(), "joining file");
+        joined.extend(resampled);
+    }
+
+    let clipping_ratio = n_clipped as f32 / n_samples.max(1) as f32;
+    let tags = tags.unwrap_or_default();
+    let Some(samplerate) = joined_samplerate else {
+        return Ok(Spectrogram {
+            frames: Vec::new(),
+            frame_starts: Vec::new(),
+            samplerate: processing.target_samplerate,
+            clipping_ratio,
+            tags,
+            audio: Vec::new(),
+        });
+    };
+    if let Some(max_seconds) = processing.max_seconds {
+        joined.truncate((max_seconds * samplerate as f64) as usize);
+    }
+
+    spectrogram_from(
+        joined,
+        samplerate,
+        clipping_ratio,
+        tags,
+        processing,
+        &spect_gen,
+    )
+}
+
+/// The audio decoded out of a single file, before any resampling
+struct DecodedAudio {
+    /// The selected channel, or every channel mixed down, or `None` if there were no channels
+    mono: Option<Vec<f32>>,
+    samplerate: usize,
+    /// The number of decoded samples across every channel
+    n_samples: usize,
+    /// How many of those samples were at or near full scale
+    n_clipped: usize,
+    tags: FileTags,
+}
+
+impl DecodedAudio {
+    fn clipping_ratio(&self) -> f32 {
+        self.n_clipped as f32 / self.n_samples.max(1) as f32
+    }
+}
+
+/// Decode `filename` into a single channel of audio at its original samplerate
+fn decode_file(
+    filename: &PathBuf,
+    processing: &ProcessingArgs,
+) -> Result<DecodedAudio, HandleFileError> {
+    debug!("opening file");
     let registry = symphonia::default::get_codecs();
     let probe = symphonia::default::get_probe();
-    let file = std::fs::File::open(filename).unwrap();
+    let source: Box<dyn symphonia::core::io::MediaSource> = match filename.as_os_str() == "-" {
+        true => {
+            // symphonia needs to be able to seek, so the whole of stdin is read into memory
+            // first, which for uncompressed audio can be a few hundred megabytes
+            let mut buf = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::stdin(), &mut buf)?;
+            Box::new(std::io::Cursor::new(buf))
+        }
+        false => Box::new(std::fs::File::open(filename)?),
+    };
     let stream = MediaSourceStream::new(
-        Box::new(file),
+        source,
         symphonia::core::io::MediaSourceStreamOptions::default(),
     );
     let mut format = probe
@@ -428,97 +1719,394 @@ fn handle_file(
             &FormatOptions::default(),
             &MetadataOptions::default(),
         )
-        .unwrap();
+        .map_err(HandleFileError::Probe)?;
 
-    let metadata = format.metadata.get();
-    debug!(?metadata, "read song");
+    let mut tags = FileTags::default();
+    // tags can be found both while probing (like id3 tags) and in the container itself
+    if let Some(revision) = format.metadata.get().as_ref().and_then(|m| m.current()) {
+        debug!(metadata = ?revision, "read probed metadata");
+        tags.read(revision);
+    }
+    if let Some(revision) = format.format.metadata().current() {
+        debug!(metadata = ?revision, "read container metadata");
+        tags.read(revision);
+    }
     let tracks = format.format.tracks();
     if tracks.len() != 1 {
         warn!(?tracks, "song had multiple tracks, using only default");
     }
-    let track = format.format.default_track().unwrap();
+    let track = format
+        .format
+        .default_track()
+        .ok_or(HandleFileError::NoDefaultTrack)?;
     let mut decoder = registry
         .make(
             &track.codec_params,
             &symphonia::core::codecs::DecoderOptions::default(),
         )
-        .unwrap();
+        .map_err(HandleFileError::Decoder)?;
     info!(params=?track.codec_params, "read codec params");
-    let samplerate = track.codec_params.sample_rate.unwrap();
+    // not every container reports this, in which case it's taken from the decoded audio
+    let mut samplerate = track.codec_params.sample_rate;
     let track_id = track.id;
 
     let mut channels: Vec<Vec<f32>> = Vec::new();
 
-    while let Ok(packet) = format.format.next_packet() {
+    loop {
+        let packet = match format.format.next_packet() {
+            Ok(packet) => packet,
+            // symphonia reports the end of the stream as an unexpected eof
+            Err(symphonia::core::errors::Error::IoError(error))
+                if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            // the tracks changed, like at the start of a new chained ogg stream, which isn't
+            // part of the song anymore
+            Err(symphonia::core::errors::Error::ResetRequired) => {
+                debug!(path = ?filename, "track list changed, stopping decoding");
+                break;
+            }
+            Err(error) => return Err(HandleFileError::Demux(error)),
+        };
         if packet.track_id() != track_id {
             continue;
         }
 
-        let decoded = decoder.decode(&packet).unwrap();
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // a corrupt packet only loses a little audio, so carry on with the rest
+            Err(symphonia::core::errors::Error::DecodeError(error)) => {
+                warn!(path = ?filename, error, "skipping undecodable packet");
+                continue;
+            }
+            Err(error) => return Err(HandleFileError::Decode(error)),
+        };
+        samplerate.get_or_insert(decoded.spec().rate);
+        // the capacity has to match for `convert`, which can be more than the decoded frames
         let mut converted: AudioBuffer<f32> =
-            AudioBuffer::new(decoded.frames() as u64, decoded.spec().to_owned());
+            AudioBuffer::new(decoded.capacity() as u64, decoded.spec().to_owned());
         decoded.convert(&mut converted);
         let planes = converted.planes();
         let planes_slice = planes.planes();
         if channels.len() != planes_slice.len() {
             trace!("resizing channels due to size mismatch");
-            channels.resize_with(planes_slice.len(), || Vec::new());
+            channels.resize_with(planes_slice.len(), Vec::new);
         }
         channels
             .iter_mut()
             .zip(planes_slice)
             .for_each(|(d, v)| d.extend(*v));
+
+        // the rest would just be thrown away, so there's no point decoding it
+        if let (Some(max_samples), Some(samplerate)) = (processing.max_seconds, samplerate) {
+            let max_samples = (max_samples * samplerate as f64) as usize;
+            if channels.iter().all(|channel| channel.len() >= max_samples) {
+                debug!(max_samples, "decoded enough audio, stopping");
+                break;
+            }
+        }
     }
 
-    // TODO: maybe do something for each channel idk?
-    let first_channel = &channels[0];
+    let samplerate = samplerate.ok_or(HandleFileError::MissingSampleRate)?;
 
-    debug!("resampling audio");
-    let mut resampler = rubato::FftFixedIn::new(
-        samplerate as usize,
-        TARGET_SAMPLERATE_HZ,
-        first_channel.len(),
-        640,
-        1,
-    )
-    .unwrap();
-    let resampled = resampler
-        .process(&[first_channel], None)
-        .unwrap()
-        .into_iter()
+    let n_samples = channels.iter().map(|channel| channel.len()).sum::<usize>();
+    let n_clipped = channels
+        .iter()
         .flatten()
-        .collect::<Vec<_>>();
+        .filter(|sample| sample.abs() >= CLIPPING_LEVEL)
+        .count();
+    let clipping_ratio = n_clipped as f32 / n_samples.max(1) as f32;
+    if clipping_ratio > CLIPPING_WARN_RATIO {
+        warn!(path = ?filename, clipping_ratio, "audio is clipped, matches may be poor");
+    }
+
+    let mono = match processing.channel {
+        ChannelSelection::Mix => mix_down(&channels),
+        ChannelSelection::Index(index) => match channels.get(index) {
+            Some(channel) => Some(channel.clone()),
+            // a file with no channels is handled below the same way whichever is selected
+            None if channels.is_empty() => None,
+            None => {
+                return Err(HandleFileError::ChannelOutOfRange {
+                    channel: index,
+                    n_channels: channels.len(),
+                })
+            }
+        },
+    };
+    let samplerate = samplerate as usize;
+    let mono = match mono {
+        Some(mut mono) => {
+            if let Some(max_seconds) = processing.max_seconds {
+                mono.truncate((max_seconds * samplerate as f64) as usize);
+            }
+            Some(mono)
+        }
+        None => {
+            warn!(path = ?filename, "audio has no channels");
+            None
+        }
+    };
+
+    Ok(DecodedAudio {
+        mono,
+        samplerate,
+        n_samples,
+        n_clipped,
+        tags,
+    })
+}
+
+/// Bring `mono` from `samplerate` to `--target-samplerate`, returning the samplerate it ended up
+/// at, which is only different with `--no-upsample`
+fn resample_audio(
+    mono: Vec<f32>,
+    samplerate: usize,
+    processing: &ProcessingArgs,
+    resamplers: &resample::ResamplerPool,
+) -> Result<(Vec<f32>, usize), HandleFileError> {
+    Ok(match samplerate {
+        samplerate if samplerate == processing.target_samplerate => {
+            debug!("audio already at target samplerate, not resampling");
+            (mono, samplerate)
+        }
+        samplerate if samplerate < processing.target_samplerate && processing.no_upsample => {
+            warn!(
+                samplerate,
+                target = processing.target_samplerate,
+                "audio is below target samplerate, keeping original samplerate"
+            );
+            (mono, samplerate)
+        }
+        samplerate => {
+            if samplerate < processing.target_samplerate {
+                warn!(
+                    samplerate,
+                    target = processing.target_samplerate,
+                    "audio is below target samplerate, upsampling"
+                );
+            }
+
+            debug!("resampling audio");
+            let resampled = resamplers
+                .resample(
+                    &mono,
+                    samplerate,
+                    processing.target_samplerate,
+                    processing.resample_quality,
+                )
+                .map_err(HandleFileError::Resample)?;
+            (resampled, processing.target_samplerate)
+        }
+    })
+}
+
+/// Filter `audio` and generate its spectrogram
+fn spectrogram_from(
+    mut audio: Vec<f32>,
+    samplerate: usize,
+    clipping_ratio: f32,
+    tags: FileTags,
+    processing: &ProcessingArgs,
+    spect_gen: &process::SpectrogramGenerator<f32>,
+) -> Result<Spectrogram, HandleFileError> {
+    let spectrogram_config = &processing.spectrogram_config();
+    let band_pass = processing.band_pass();
+    debug!(?band_pass, "filtering audio");
+    band_pass
+        .apply(&mut audio, samplerate)
+        .map_err(HandleFileError::BandPass)?;
 
     debug!("generating spectrogram");
-    let spect_gen: process::SpectrogramGenerator<f32> = process::SpectrogramGenerator::default();
     let start = std::time::Instant::now();
-    let spectrogram = spect_gen.run(&resampled, &spectrogram_config);
+    let frame_starts = spectrogram_config.frame_starts(&audio);
+    let frames = spect_gen.run_at(&audio, spectrogram_config, &frame_starts);
     let elapsed = start.elapsed();
     debug!(?elapsed, "spectrogram generated");
-    spectrogram
+
+    Ok(Spectrogram {
+        frames,
+        frame_starts,
+        samplerate,
+        clipping_ratio,
+        tags,
+        audio,
+    })
+}
+
+/// Why audio couldn't be turned into a spectrogram
+#[derive(Debug)]
+enum HandleFileError {
+    Io(std::io::Error),
+    /// The format of the file couldn't be detected, or isn't supported
+    Probe(symphonia::core::errors::Error),
+    NoDefaultTrack,
+    /// Neither the track nor any decoded audio had a samplerate
+    MissingSampleRate,
+    /// Files being joined ended up at different samplerates
+    SampleRateMismatch {
+        path: PathBuf,
+        samplerate: usize,
+        expected: usize,
+    },
+    /// `--channel` selected a channel the audio doesn't have
+    ChannelOutOfRange {
+        channel: usize,
+        n_channels: usize,
+    },
+    /// No decoder could be made for the track's codec
+    Decoder(symphonia::core::errors::Error),
+    Decode(symphonia::core::errors::Error),
+    /// The next packet couldn't be read out of the file
+    Demux(symphonia::core::errors::Error),
+    Resample(Box<dyn std::error::Error + Send + Sync>),
+    /// `--low-cut-hz` or `--high-cut-hz` don't make a valid filter at the audio's samplerate
+    BandPass(process::ConfigError),
+}
+
+impl std::fmt::Display for HandleFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(error) => write!(f, "failed to read audio: {error}"),
+            Self::Probe(error) => write!(f, "failed to detect audio format: {error}"),
+            Self::NoDefaultTrack => write!(f, "audio has no default track"),
+            Self::MissingSampleRate => write!(f, "audio track has no samplerate"),
+            Self::SampleRateMismatch {
+                path,
+                samplerate,
+                expected,
+            } => write!(
+                f,
+                "{path:?} is at {samplerate}Hz but the files before it are at {expected}Hz"
+            ),
+            Self::ChannelOutOfRange {
+                channel,
+                n_channels,
+            } => write!(
+                f,
+                "channel {channel} was selected but the audio only has {n_channels} channels"
+            ),
+            Self::Decoder(error) => write!(f, "failed to create decoder: {error}"),
+            Self::Decode(error) => write!(f, "failed to decode audio: {error}"),
+            Self::Demux(error) => write!(f, "failed to read audio packet: {error}"),
+            Self::Resample(error) => write!(f, "failed to resample audio: {error}"),
+            Self::BandPass(error) => write!(f, "failed to filter audio: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for HandleFileError {}
+
+impl From<std::io::Error> for HandleFileError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Average every channel into a single one, or `None` if there are no channels
+fn mix_down(channels: &[Vec<f32>]) -> Option<Vec<f32>> {
+    let len = channels.iter().map(|channel| channel.len()).max()?;
+    let mut mono = vec![0.0; len];
+    for channel in channels {
+        mono.iter_mut()
+            .zip(channel)
+            .for_each(|(mixed, sample)| *mixed += sample);
+    }
+
+    let scale = 1.0 / channels.len() as f32;
+    mono.iter_mut().for_each(|sample| *sample *= scale);
+
+    Some(mono)
+}
+
+/// A spectrogram along with the offset of each frame within the resampled audio
+struct Spectrogram {
+    frames: Vec<Vec<f32>>,
+    frame_starts: Vec<usize>,
+    /// The samplerate the spectrogram was generated at, which all offsets are relative to
+    samplerate: usize,
+    /// The fraction of decoded samples that were at or near full scale
+    clipping_ratio: f32,
+    tags: FileTags,
+    /// The mono, resampled and filtered audio the frames were generated from
+    audio: Vec<f32>,
+}
+
+/// The tags read out of an audio file's metadata
+#[derive(Debug, Default)]
+struct FileTags {
+    title: Option<String>,
+    artist: Option<String>,
+}
+
+impl FileTags {
+    /// Fill in any tags that haven't been found yet from `revision`
+    fn read(&mut self, revision: &symphonia::core::meta::MetadataRevision) {
+        use symphonia::core::meta::StandardTagKey;
+
+        for tag in revision.tags() {
+            let value = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                Some(StandardTagKey::Artist) => &mut self.artist,
+                _ => continue,
+            };
+            // riff info chunks keep their nul terminators
+            let tag_value = tag.value.to_string();
+            let tag_value = tag_value.trim_end_matches('\0').trim();
+            if !tag_value.is_empty() {
+                value.get_or_insert_with(|| tag_value.to_string());
+            }
+        }
+    }
+
+    /// A title in the same form as `--title`, including the artist if there is one
+    fn title(&self) -> Option<String> {
+        let title = self.title.as_ref()?;
+        Some(match &self.artist {
+            Some(artist) => format!("{artist} - {title}"),
+            None => title.clone(),
+        })
+    }
 }
 
 #[instrument(skip_all, level = "trace")]
+/// Insert a song, returning `None` if its spectrogram couldn't be stored
 async fn persist_to_db(
     db: database::Database,
-    spectrogram: Vec<Vec<f32>>,
+    spectrogram: Spectrogram,
     song_metadata: &database::models::SongMetadata,
     spectrogram_config: &process::SpectrogramConfig,
-) -> i64 {
-    let song_id = db
-        .insert_new_song(
-            spectrogram,
+) -> Option<database::models::InsertResult> {
+    let inserted = match db
+        .insert_new_song_at(
+            spectrogram.frames,
             song_metadata,
-            TARGET_SAMPLERATE_HZ,
+            spectrogram.samplerate,
             spectrogram_config.fft_len,
             spectrogram_config.overlap,
+            &spectrogram.frame_starts,
         )
         .await
-        .expect("failed to insert song");
+    {
+        Ok(inserted) => inserted,
+        Err(database::InsertError::NonFiniteSegment { segment_index }) => {
+            warn!(segment_index, metadata=?song_metadata, "spectrogram contains non-finite values, skipping");
+            return None;
+        }
+        Err(error) => panic!("failed to insert song: {error}"),
+    };
 
-    info!(song_id, metadata=?song_metadata, spec_cofig=?spectrogram_config, "inserted song");
+    info!(
+        song_id = inserted.song_id,
+        duration_ms = inserted.duration_ms,
+        segment_count = inserted.segment_count,
+        metadata=?song_metadata,
+        spec_cofig=?spectrogram_config,
+        "inserted song"
+    );
 
-    song_id
+    Some(inserted)
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -541,18 +2129,100 @@ struct ParsedDate {
     year: usize,
 }
 
+impl ParsedDate {
+    fn to_date(&self) -> time::Date {
+        time::Date::parse(
+            &format!("{:02}/{:02}/{}", self.day, self.month, self.year),
+            DATE_FORMAT,
+        )
+        .expect("failed to parse date somehow")
+    }
+}
+
+/// A [`DiscoverResult`] for one of many query files
+#[derive(Debug, Clone, serde::Serialize)]
+struct DiscoverFileResult {
+    path: PathBuf,
+    #[serde(flatten)]
+    result: DiscoverResult,
+}
+
 #[derive(Debug, Clone, serde::Serialize)]
 struct DiscoverResult {
     entries: Vec<DiscoverEntry>,
+    /// The fraction of the query's samples that were clipped
+    clipping_ratio: f32,
+    stats: MatchStats,
     timings: DiscoverTimings,
 }
 
+/// How well the query's segments matched anything at all, to help tell whether
+/// `--max-distance` suits the query
+#[derive(Debug, Clone, Default, serde::Serialize)]
+struct MatchStats {
+    /// How many of the query's segments were searched for, which skips some with
+    /// `--sample-every`
+    segments_total: usize,
+    /// How many segments had at least one neighbour within `max_distance`
+    segments_with_matches: usize,
+    max_distance: f64,
+    /// The distances of every neighbour of every segment, `None` if there were none
+    distance: Option<DistanceSummary>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+struct DistanceSummary {
+    min: f64,
+    mean: f64,
+    max: f64,
+    /// How many distances were summarised
+    count: usize,
+}
+
+impl MatchStats {
+    fn add(&mut self, neighbours: &[database::models::SimilarMatch]) {
+        if !neighbours.is_empty() {
+            self.segments_with_matches += 1;
+        }
+
+        for distance in neighbours.iter().map(|neighbour| neighbour.distance) {
+            let summary = self.distance.get_or_insert(DistanceSummary {
+                min: f64::INFINITY,
+                mean: 0.0,
+                max: f64::NEG_INFINITY,
+                count: 0,
+            });
+            summary.min = summary.min.min(distance);
+            summary.max = summary.max.max(distance);
+            summary.count += 1;
+            // a running mean, so this doesn't need to hold onto every distance
+            summary.mean += (distance - summary.mean) / summary.count as f64;
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Serialize)]
 struct DiscoverEntry {
     song: Song,
     singer_name: String,
     score: usize,
+    /// `score` as a fraction of the highest possible score for the query, from 0 to 1
+    confidence: f64,
     song_duration_ms: i64,
+    /// How many segments are stored for this song
+    segment_count: i64,
+    /// How many of this song's segments were matched by at least one query segment
+    matched_segment_count: usize,
+    /// The query segments that matched this song, only included with `--verbose-matches`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    matches: Option<Vec<MatchedSegment>>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct MatchedSegment {
+    query_segment_index: usize,
+    db_segment_index: i64,
+    distance: f64,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
@@ -574,8 +2244,174 @@ impl From<database::models::Song> for Song {
     }
 }
 
+/// A single line of the `--dump-matches` output
+#[derive(Debug, Clone, serde::Serialize)]
+struct FrameMatches {
+    index: usize,
+    start_ms: f64,
+    neighbours: Vec<Neighbour>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct Neighbour {
+    song_id: i64,
+    segment_index: i64,
+    distance: f64,
+}
+
 #[derive(Debug, Clone, serde::Serialize)]
 struct DiscoverTimings {
     spectrogram: std::time::Duration,
     query: std::time::Duration,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::memory::InMemoryDatabase;
+
+    /// `discover` parsed from `args`, with every other option left at its default
+    fn discover_args(args: &[&str]) -> DiscoverArgs {
+        let args = ["process_cli", "discover", "--db", "unused"]
+            .iter()
+            .chain(args);
+        match Command::try_parse_from(args).unwrap() {
+            Command::Discover(args) => args,
+            command => panic!("parsed {command:?} rather than discover"),
+        }
+    }
+
+    #[test]
+    fn every_command_parses() {
+        <Command as clap::CommandFactory>::command().debug_assert();
+    }
+
+    #[test]
+    fn dry_runs_dont_need_a_database() {
+        let upload_bulk = [
+            "process_cli",
+            "upload-bulk",
+            "songs",
+            "--shell-script",
+            "parse.sh",
+        ];
+
+        let dry_run = Command::try_parse_from(upload_bulk.iter().chain(&["--dry-run"])).unwrap();
+        assert!(matches!(
+            dry_run,
+            Command::UploadBulk {
+                db: None,
+                dry_run: true,
+                ..
+            }
+        ));
+        assert!(Command::try_parse_from(upload_bulk).is_err());
+    }
+
+    fn test_db() -> InMemoryDatabase {
+        let db = InMemoryDatabase::default();
+        db.insert_singer(database::models::Singer {
+            id: 0,
+            name: "test singer".to_string(),
+        });
+        db
+    }
+
+    fn store(db: &InMemoryDatabase, title: &str, frames: Vec<Vec<f32>>) -> i64 {
+        let duration_ms = frames.len() as i64 * 32;
+        db.insert_song(
+            database::models::SongMetadata {
+                title: title.to_string(),
+                singer_id: 0,
+                date_first_sung: None,
+                local_path: None,
+                joined_paths: Vec::new(),
+            },
+            frames,
+            duration_ms,
+        )
+    }
+
+    /// Frames that are each 100 apart from the last, and at least 1000 from any other song's
+    fn song_frames(song: usize) -> Vec<Vec<f32>> {
+        (0..30)
+            .map(|frame| {
+                let mut vector = vec![0.0; 8];
+                vector[0] = frame as f32 * 100.0;
+                vector[1] = song as f32 * 1000.0;
+                vector
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn the_song_a_query_comes_from_scores_highest() {
+        let db = test_db();
+        let first = store(&db, "first", song_frames(1));
+        let second = store(&db, "second", song_frames(2));
+        let query = song_frames(2)[10..20].to_vec();
+
+        for scoring in ["rank", "offset", "distance"] {
+            let args = discover_args(&["--scoring", scoring, "-"]);
+            let scores = score_spectrogram(&db, &query, &args.query, 10, |_, _| {}).await;
+
+            assert_eq!(scores.n_queried, query.len());
+            assert!(
+                scores.ranked.iter().all(|(song_id, _)| *song_id != first),
+                "{scoring} gave a score to a song the query doesn't match: {:?}",
+                scores.ranked
+            );
+            assert_eq!(
+                scores.ranked.first().map(|(song_id, _)| *song_id),
+                Some(second),
+                "{scoring}"
+            );
+        }
+    }
+
+    fn write_tone(name: &str, frequency: f32) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("plink-{}-{name}.wav", std::process::id()));
+        let samples = (0..TARGET_SAMPLERATE_HZ * 2)
+            .map(|i| {
+                let t = i as f32 / TARGET_SAMPLERATE_HZ as f32;
+                (t * frequency * std::f32::consts::TAU).sin() * 0.5
+            })
+            .collect::<Vec<_>>();
+        wav::write_mono(&path, &samples, TARGET_SAMPLERATE_HZ).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn discover_asks_for_more_matches_than_there_are_songs() {
+        let db = test_db();
+        let low = write_tone("low", 440.0);
+        let high = write_tone("high", 3000.0);
+        let args = discover_args(&["--n-matches", "10", low.to_str().unwrap()]);
+        let low_id = store(
+            &db,
+            "low",
+            handle_file(&low, &args.processing).unwrap().frames,
+        );
+        store(
+            &db,
+            "high",
+            handle_file(&high, &args.processing).unwrap().frames,
+        );
+
+        let result = discover_file(
+            &db,
+            &process::SpectrogramGenerator::default(),
+            &resample::ResamplerPool::default(),
+            &low,
+            &args,
+        )
+        .await;
+        std::fs::remove_file(&low).unwrap();
+        std::fs::remove_file(&high).unwrap();
+
+        let result = result.expect("the query file should be processed");
+        assert!(!result.entries.is_empty());
+        assert!(result.entries.len() <= 2);
+        assert_eq!(result.entries[0].song.id, low_id);
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// How results are written out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Only log the results
+    Text,
+    Json,
+    /// A header row, then one row per result
+    Csv,
+}
+
+/// Quote a csv field if it contains anything that would otherwise break the row up
+pub fn csv_field(value: &str) -> std::borrow::Cow<'_, str> {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")).into(),
+        false => value.into(),
+    }
+}
+
+/// Where machine-readable output should be written
+#[derive(Debug, Clone)]
+pub enum OutputTarget {
+    Stdout,
+    /// Written atomically, so that anything watching the file never sees partial output
+    File(PathBuf),
+}
+
+/// Parses `-` as stdout, and anything else as a file path
+pub fn parse_target(value: &str) -> Result<OutputTarget, String> {
+    match value {
+        "-" => Ok(OutputTarget::Stdout),
+        "" => Err("output path can't be empty".to_string()),
+        path => Ok(OutputTarget::File(PathBuf::from(path))),
+    }
+}
+
+impl OutputTarget {
+    pub fn write(&self, contents: &str) -> std::io::Result<()> {
+        match self {
+            OutputTarget::Stdout => {
+                let mut stdout = std::io::stdout().lock();
+                writeln!(stdout, "{contents}")?;
+                stdout.flush()
+            }
+            OutputTarget::File(path) => write_atomic(path, contents),
+        }
+    }
+}
+
+/// Write to a temporary file next to `path` then rename it into place, renames within a
+/// directory being atomic
+fn write_atomic(path: &Path, contents: &str) -> std::io::Result<()> {
+    let file_name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "output path has no file name",
+        )
+    })?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(file_name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&temp_path)?;
+        writeln!(file, "{contents}")?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    result
+}
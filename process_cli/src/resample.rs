@@ -0,0 +1,146 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use rubato::Resampler;
+
+/// How many input samples are resampled at a time, resamplers have a fixed input size so this
+/// lets one be reused for audio of any length
+const CHUNK_SIZE: usize = 1 << 14;
+
+/// The speed/quality tradeoff used when resampling
+///
+/// The resampler slightly changes the resulting spectrograms, so songs should be uploaded and
+/// discovered with the same quality
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, clap::ValueEnum)]
+pub enum ResampleQuality {
+    /// An fft resampler split into many small ffts, with a less steep anti-aliasing filter
+    Fast,
+    /// An fft resampler with larger ffts
+    #[default]
+    Balanced,
+    /// A windowed sinc resampler, much slower but with the least aliasing
+    High,
+}
+
+impl ResampleQuality {
+    fn build(
+        self,
+        from: usize,
+        to: usize,
+    ) -> Result<AnyResampler, rubato::ResamplerConstructionError> {
+        // the number of ffts each chunk is split into, see [`rubato::FftFixedIn::new`]
+        let sub_chunks = match self {
+            Self::Fast => 64,
+            Self::Balanced => 16,
+            Self::High => {
+                let parameters = rubato::SincInterpolationParameters {
+                    sinc_len: 256,
+                    f_cutoff: 0.95,
+                    oversampling_factor: 256,
+                    interpolation: rubato::SincInterpolationType::Cubic,
+                    window: rubato::WindowFunction::BlackmanHarris2,
+                };
+                return Ok(AnyResampler::Sinc(rubato::SincFixedIn::new(
+                    to as f64 / from as f64,
+                    1.0,
+                    parameters,
+                    CHUNK_SIZE,
+                    1,
+                )?));
+            }
+        };
+
+        Ok(AnyResampler::Fft(rubato::FftFixedIn::new(
+            from, to, CHUNK_SIZE, sub_chunks, 1,
+        )?))
+    }
+}
+
+enum AnyResampler {
+    Fft(rubato::FftFixedIn<f32>),
+    Sinc(rubato::SincFixedIn<f32>),
+}
+
+type Key = (usize, usize, ResampleQuality);
+
+/// Resamplers kept around between files, keyed by `(from, to)` samplerates and quality
+///
+/// Each resampler can only be used by one file at a time, so there may be several for the same
+/// samplerates when files are processed concurrently
+#[derive(Clone, Default)]
+pub struct ResamplerPool {
+    idle: Arc<Mutex<HashMap<Key, Vec<AnyResampler>>>>,
+}
+
+impl ResamplerPool {
+    /// Resample mono `samples` from `from` hz to `to` hz
+    pub fn resample(
+        &self,
+        samples: &[f32],
+        from: usize,
+        to: usize,
+        quality: ResampleQuality,
+    ) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+        let key = (from, to, quality);
+        let idle = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(|idle| idle.pop());
+        let mut resampler = match idle {
+            Some(mut resampler) => {
+                match &mut resampler {
+                    AnyResampler::Fft(resampler) => resampler.reset(),
+                    AnyResampler::Sinc(resampler) => resampler.reset(),
+                }
+                resampler
+            }
+            None => quality.build(from, to)?,
+        };
+
+        let result = match &mut resampler {
+            AnyResampler::Fft(resampler) => resample_with(resampler, samples, from, to),
+            AnyResampler::Sinc(resampler) => resample_with(resampler, samples, from, to),
+        };
+        self.idle
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_default()
+            .push(resampler);
+
+        result
+    }
+}
+
+fn resample_with(
+    resampler: &mut impl Resampler<f32>,
+    samples: &[f32],
+    from: usize,
+    to: usize,
+) -> Result<Vec<f32>, Box<dyn std::error::Error + Send + Sync>> {
+    let expected_len = (samples.len() * to).div_ceil(from);
+    let delay = resampler.output_delay();
+    let mut out = Vec::with_capacity(expected_len + delay + resampler.output_frames_max());
+
+    for chunk in samples.chunks(CHUNK_SIZE) {
+        let resampled = match chunk.len() == CHUNK_SIZE {
+            true => resampler.process(&[chunk], None)?,
+            false => resampler.process_partial(Some(&[chunk]), None)?,
+        };
+        out.extend_from_slice(&resampled[0]);
+    }
+
+    // flush out whatever is still buffered, until the delayed output has all come out
+    while out.len() < expected_len + delay {
+        let resampled = resampler.process_partial::<&[f32]>(None, None)?;
+        out.extend_from_slice(&resampled[0]);
+    }
+
+    out.drain(..delay);
+    out.truncate(expected_len);
+    Ok(out)
+}
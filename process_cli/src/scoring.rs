@@ -0,0 +1,154 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+/// Accumulates scores per song as query results stream in
+///
+/// When a `capacity` is given at most that many songs are tracked at once, using the
+/// space-saving algorithm: once full, a newly seen song replaces the lowest scoring one and
+/// inherits its score. This bounds memory at the cost of possibly overestimating the scores of
+/// songs that were only seen late, which is fine when only the top few matches matter.
+pub struct ScoreBoard {
+    scores: HashMap<i64, usize>,
+    capacity: Option<usize>,
+    /// min-heap of `(score, song_id)`, only maintained when bounded
+    ///
+    /// This can contain stale entries for songs whose scores have since increased, these are
+    /// skipped when evicting and cleared out whenever the heap grows too large
+    heap: BinaryHeap<Reverse<(usize, i64)>>,
+}
+
+impl ScoreBoard {
+    pub fn new(capacity: Option<usize>) -> Self {
+        Self {
+            scores: HashMap::new(),
+            capacity: capacity.map(|capacity| capacity.max(1)),
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    pub fn add(&mut self, song_id: i64, score: usize) {
+        let total = match self.scores.get_mut(&song_id) {
+            Some(current) => {
+                *current += score;
+                *current
+            }
+            None => {
+                let inherited = match self.capacity {
+                    Some(capacity) if self.scores.len() >= capacity => self.evict_min(),
+                    _ => 0,
+                };
+                self.scores.insert(song_id, inherited + score);
+                inherited + score
+            }
+        };
+
+        if let Some(capacity) = self.capacity {
+            self.heap.push(Reverse((total, song_id)));
+
+            if self.heap.len() > capacity * 2 {
+                self.heap = self
+                    .scores
+                    .iter()
+                    .map(|(song_id, score)| Reverse((*score, *song_id)))
+                    .collect();
+            }
+        }
+    }
+
+    /// Remove the lowest scoring song, returning its score
+    fn evict_min(&mut self) -> usize {
+        while let Some(Reverse((score, song_id))) = self.heap.pop() {
+            if self.scores.get(&song_id) == Some(&score) {
+                self.scores.remove(&song_id);
+                return score;
+            }
+        }
+
+        0
+    }
+
+    /// The `n` highest scoring `(song_id, score)` pairs, best first
+    pub fn top(self, n: usize) -> Vec<(i64, usize)> {
+        let mut heap = BinaryHeap::with_capacity(n.min(self.scores.len()).saturating_add(1));
+
+        for (song_id, score) in self.scores {
+            heap.push(Reverse((score, song_id)));
+            if heap.len() > n {
+                heap.pop();
+            }
+        }
+
+        heap.into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((score, song_id))| (song_id, score))
+            .collect()
+    }
+}
+
+/// How the neighbours of each query frame are turned into a score per song
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ScoringMethod {
+    /// Sum how highly each song ranked among every frame's neighbours
+    #[default]
+    Rank,
+    /// Count the frames whose neighbours line up with the song at its most common time offset,
+    /// so songs that only share a few common sounds with the query score poorly
+    Offset,
+    /// Sum how close each neighbour was, so a few very close matches outweigh lots of distant
+    /// ones
+    Distance,
+}
+
+/// The score of a neighbour at zero distance with [`ScoringMethod::Distance`], scores are
+/// integers so this keeps enough precision to tell close distances apart
+pub const DISTANCE_SCORE_SCALE: usize = 1000;
+
+/// Scale `max_distance - distance` to between 0 and [`DISTANCE_SCORE_SCALE`]
+pub fn distance_score(distance: f64, max_distance: f64) -> usize {
+    if max_distance <= 0.0 {
+        return DISTANCE_SCORE_SCALE;
+    }
+
+    let closeness = (1.0 - distance / max_distance).clamp(0.0, 1.0);
+    (closeness * DISTANCE_SCORE_SCALE as f64).round() as usize
+}
+
+/// Finds, for each song, the most common offset between the query frames and the song
+/// segments they matched
+///
+/// If query frame `i` matched segment `j` of a song, the query probably started around segment
+/// `j - i` of that song. A real match has most of its frames agreeing on that offset, while
+/// a song that just shares a few sounds with the query has them scattered.
+pub struct OffsetHistogram {
+    /// `song_id -> (offset -> count)`
+    offsets: HashMap<i64, HashMap<i64, usize>>,
+}
+
+impl OffsetHistogram {
+    pub fn new() -> Self {
+        Self {
+            offsets: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, song_id: i64, query_index: usize, segment_index: i64) {
+        *self
+            .offsets
+            .entry(song_id)
+            .or_default()
+            .entry(segment_index - query_index as i64)
+            .or_default() += 1;
+    }
+
+    /// The `n` songs with the highest peak offset count, as `(song_id, count)`, best first
+    pub fn top(self, n: usize) -> Vec<(i64, usize)> {
+        let mut board = ScoreBoard::new(None);
+        for (song_id, offsets) in self.offsets {
+            board.add(song_id, offsets.into_values().max().unwrap_or(0));
+        }
+
+        board.top(n)
+    }
+}
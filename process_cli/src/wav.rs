@@ -0,0 +1,18 @@
+use std::path::Path;
+
+/// Write mono `samples` to a 16-bit pcm wav, clamping them to `-1.0..=1.0` first
+pub fn write_mono(path: &Path, samples: &[f32], samplerate: usize) -> hound::Result<()> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: samplerate as u32,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+
+    for sample in samples {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+
+    writer.finalize()
+}